@@ -0,0 +1,79 @@
+//! Access control for client-to-client traffic in hub mode
+//!
+//! A rule names two subnets whose clients may talk to each other, in
+//! both directions. With no rules every client may reach every other.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use ipnet::IpNet;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AclRuleError {
+    #[error("Invalid ACL rule {0}, expected SUBNET-SUBNET")]
+    Format(String),
+    #[error("Invalid ACL subnet: {0}")]
+    Subnet(#[from] ipnet::AddrParseError),
+}
+
+/// Two subnets allowed to exchange traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclRule {
+    a: IpNet,
+    b: IpNet,
+}
+
+impl AclRule {
+    /// Check whether the rule allows a packet from `src` to `dst`
+    pub fn matches(&self, src: IpAddr, dst: IpAddr) -> bool {
+        (self.a.contains(&src) && self.b.contains(&dst))
+            || (self.b.contains(&src) && self.a.contains(&dst))
+    }
+}
+
+impl FromStr for AclRule {
+    type Err = AclRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (a, b) = s
+            .split_once('-')
+            .ok_or_else(|| AclRuleError::Format(s.to_string()))?;
+        Ok(Self {
+            a: a.trim().parse::<IpNet>()?.trunc(),
+            b: b.trim().parse::<IpNet>()?.trunc(),
+        })
+    }
+}
+
+impl fmt::Display for AclRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.a, self.b)
+    }
+}
+
+/// Check a packet against a rule list; an empty list allows everything
+pub fn is_allowed(rules: &[AclRule], src: IpAddr, dst: IpAddr) -> bool {
+    rules.is_empty() || rules.iter().any(|rule| rule.matches(src, dst))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acl_rules() {
+        let rules: Vec<AclRule> = ["10.0.1.0/24-10.0.2.0/24", "fd00::/64-fd00::/64"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        let allowed = |src: &str, dst: &str| is_allowed(&rules, src.parse().unwrap(), dst.parse().unwrap());
+
+        assert!(allowed("10.0.1.5", "10.0.2.7"));
+        assert!(allowed("10.0.2.7", "10.0.1.5"));
+        assert!(!allowed("10.0.1.5", "10.0.1.6"));
+        assert!(allowed("fd00::2", "fd00::3"));
+        assert!(is_allowed(&[], "10.0.1.5".parse().unwrap(), "10.0.1.6".parse().unwrap()));
+        assert!("10.0.1.0/24".parse::<AclRule>().is_err());
+    }
+}
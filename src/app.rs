@@ -1,29 +1,41 @@
 //! Application core logic
 
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+#[cfg(target_os = "macos")]
 use std::process::Command;
 use std::sync::Arc;
-use std::time::Duration;
-use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use dashmap::DashSet;
+use ipnet::IpNet;
 use prost::Message;
-use rand::Rng;
-use tokio::sync::Mutex as TokioMutex;
 use tracing::{debug, error, info, warn};
 
+use crate::acl;
 use crate::config::get_config;
-use crate::iface::{Iface, PacketIP};
-use crate::protocol::{Envelope, MessagePacket, MessagePing, envelope};
+use crate::iface::nat::EgressNat;
+use crate::iface::{Iface, IfaceReader, IfaceWriter, PacketIP};
+use crate::pool::AddressPool;
+use crate::conflict::{Claim, VipOwners};
+use crate::protocol::{Envelope, MessageLease, MessagePacket, envelope};
+use crate::route::RouteTable;
+use crate::split::{SplitChanges, SplitRoutes};
 use crate::transport::{Client, Server, ServerConn, TransportHandler};
 use crate::utils::Timer;
 
-/// Route table: maps destination IP to set of connection addresses
-type RouteTable = DashMap<String, HashSet<String>>;
+/// Shortest time between two logs of packets dropped for lack of a route
+const NO_ROUTE_LOG_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct App {
     routes: Arc<RouteTable>,
     server: Option<Arc<Server<AppHandler>>>,
-    client: Option<Arc<TokioMutex<Client<AppHandler>>>>,
-    iface: Option<Arc<TokioMutex<Iface>>>,
+    client: Option<Arc<Client<AppHandler>>>,
+    iface: Option<Arc<Iface>>,
+    nat: Option<EgressNat>,
+    /// Virtual addresses of the interface, leased or from the config
+    ip: String,
+    ip6: Option<String>,
     timer: Timer,
 }
 
@@ -31,15 +43,33 @@ pub struct App {
 pub struct AppHandler {
     routes: Arc<RouteTable>,
     server: Arc<parking_lot::RwLock<Option<Arc<Server<AppHandler>>>>>,
-    iface: Arc<parking_lot::RwLock<Option<Arc<TokioMutex<Iface>>>>>,
+    iface: Arc<parking_lot::RwLock<Option<IfaceWriter>>>,
+    /// TUN interface, for kernel routes to advertised subnets
+    tun: Arc<parking_lot::RwLock<Option<Arc<Iface>>>>,
+    /// Advertised subnets already reported as not accepted
+    rejected_routes: DashSet<IpNet>,
+    /// Client identity owning each advertised subnet
+    route_owners: parking_lot::Mutex<HashMap<IpNet, String>>,
+    /// Client address pool, server only
+    pool: Option<AddressPool>,
+    /// Client owning each announced virtual IP
+    owners: VipOwners<ServerConn>,
+    /// Route table version last logged
+    routes_logged: AtomicU64,
 }
 
 impl AppHandler {
-    pub fn new(routes: Arc<RouteTable>) -> Self {
+    pub fn new(routes: Arc<RouteTable>, pool: Option<AddressPool>) -> Self {
         Self {
             routes,
+            pool,
             server: Arc::new(parking_lot::RwLock::new(None)),
             iface: Arc::new(parking_lot::RwLock::new(None)),
+            tun: Arc::new(parking_lot::RwLock::new(None)),
+            rejected_routes: DashSet::new(),
+            route_owners: parking_lot::Mutex::new(HashMap::new()),
+            owners: VipOwners::new(),
+            routes_logged: AtomicU64::new(u64::MAX),
         }
     }
 
@@ -47,41 +77,241 @@ impl AppHandler {
         *self.server.write() = Some(server);
     }
 
-    pub fn set_iface(&self, iface: Arc<TokioMutex<Iface>>) {
+    pub fn set_iface(&self, tun: Arc<Iface>, iface: IfaceWriter) {
+        *self.tun.write() = Some(tun);
         *self.iface.write() = Some(iface);
     }
-}
 
-impl TransportHandler for AppHandler {
-    fn client_on_data(&self, data: Vec<u8>) {
-        let env = match Envelope::decode(data.as_slice()) {
-            Ok(e) => e,
-            Err(e) => {
-                error!(error = %e, "Failed to decode envelope");
-                return;
+    /// Claim the virtual IPs announced by a connection. Connections of
+    /// the same client share them; another client's claim is resolved by
+    /// the conflict policy. Returns false when `conn` was rejected
+    fn claim_vips(&self, vips: &[IpAddr], conn: &Arc<ServerConn>) -> bool {
+        let policy = get_config().ip_conflict;
+        match self.owners.claim(vips, conn.identity(), conn, policy, |c| !c.is_closed()) {
+            Claim::Granted => true,
+            Claim::Conflict { ip, owner } => {
+                warn!(ip = %ip, identity = %conn.identity(), owner = %owner, "Virtual IP conflict, rejecting client");
+                conn.conflict(&format!("virtual ip {ip} is in use by another client"));
+                false
+            }
+            Claim::TakenOver { previous, conns } => {
+                for (ip, previous) in &previous {
+                    warn!(
+                        ip = %ip,
+                        identity = %conn.identity(),
+                        previous = %previous,
+                        "Virtual IP conflict, newest client takes over"
+                    );
+                }
+                let ips: Vec<String> = previous.iter().map(|(ip, _)| ip.to_string()).collect();
+                for old in conns {
+                    old.conflict(&format!("virtual ip {} was taken over by another client", ips.join(", ")));
+                }
+                true
+            }
+        }
+    }
+
+    /// Install subnets advertised by a client, if the server accepts them,
+    /// and return the accepted ones. A subnet must be granted by the
+    /// client certificate, when client auth is on, and must not overlap
+    /// a subnet another client advertises
+    fn install_advertised_routes(&self, routes: &[String], conn: &ServerConn, hop: &str) -> Vec<IpNet> {
+        let accept = &get_config().accept_routes;
+        let mut accepted = Vec::new();
+
+        for route in routes {
+            let net = match route.parse::<IpNet>() {
+                Ok(net) => net.trunc(),
+                Err(e) => {
+                    warn!(route = %route, error = %e, "Invalid advertised route ignored");
+                    continue;
+                }
+            };
+            if !accept.iter().any(|accepted| accepted.contains(&net)) {
+                if self.rejected_routes.insert(net) {
+                    warn!(route = %net, conn = %hop, "Advertised route not accepted, see --accept-routes");
+                }
+                continue;
+            }
+            if !conn.is_route_allowed(&net) {
+                if self.rejected_routes.insert(net) {
+                    warn!(route = %net, conn = %hop, "Advertised route not granted by the client certificate");
+                }
+                continue;
+            }
+            if let Err(owner) = self.claim_route(net, conn.identity()) {
+                if self.rejected_routes.insert(net) {
+                    warn!(
+                        route = %net,
+                        conn = %hop,
+                        identity = %conn.identity(),
+                        owner = %owner,
+                        "Advertised route overlaps another client's, rejecting"
+                    );
+                }
+                continue;
             }
+
+            if self.routes.insert(net, hop.to_string()) {
+                self.update_kernel_route(net, true);
+            }
+            accepted.push(net);
+        }
+        accepted
+    }
+
+    /// Record `identity` as the owner of an advertised subnet, or return
+    /// the client owning an overlapping one
+    fn claim_route(&self, net: IpNet, identity: &str) -> Result<(), String> {
+        let mut owners = self.route_owners.lock();
+        let taken = owners
+            .iter()
+            .find(|(other, owner)| *owner != identity && (other.contains(&net) || net.contains(*other)));
+        if let Some((_, owner)) = taken {
+            return Err(owner.clone());
+        }
+        owners.entry(net).or_insert_with(|| identity.to_string());
+        Ok(())
+    }
+
+    /// Forget virtual IP owners whose connections are all closed
+    pub fn prune_owners(&self) {
+        self.owners.prune(|c| !c.is_closed());
+    }
+
+    /// Remove a next hop from a route, deleting the kernel route and the
+    /// subnet's owner along with the last hop
+    pub fn remove_route(&self, net: &IpNet, hop: &str) {
+        if self.routes.remove(net, hop) {
+            self.route_owners.lock().remove(net);
+            self.update_kernel_route(*net, false);
+        }
+    }
+
+    fn update_kernel_route(&self, net: IpNet, add: bool) {
+        let Some(tun) = self.tun.read().clone() else {
+            return;
         };
+        tokio::spawn(async move {
+            // Only advertised subnets are ever added, deleting others is a no-op
+            let result = if add {
+                tun.add_route(net).await
+            } else {
+                tun.delete_route(net).await
+            };
+            if let Err(e) = result {
+                warn!(route = %net, error = %e, "Failed to update kernel route");
+            }
+        });
+    }
 
-        if let Some(envelope::Type::Packet(packet)) = env.r#type {
+    /// Handle IP packets from a client. Packets from addresses the client
+    /// did not register are dropped. In hub mode, packets for other
+    /// clients are forwarded directly, the rest go to the TUN interface
+    fn client_packets(&self, packets: Vec<MessagePacket>, from: &Arc<ServerConn>) {
+        let mut pkts = Vec::with_capacity(packets.len());
+        for packet in packets {
             let pkt = PacketIP::from_bytes(packet.payload);
-            
+            let src = pkt.source_ip();
+            if from.is_source_allowed(src) {
+                pkts.push(pkt);
+            } else if is_link_local(src) {
+                // Link-local traffic such as router solicitations never leaves the link
+                debug!(src = %src, "Link-local packet dropped");
+            } else if let Some(dropped) = from.record_spoofed() {
+                warn!(
+                    src = %src,
+                    dst = %pkt.destination_ip(),
+                    dropped = dropped,
+                    "Packet with unregistered source address dropped"
+                );
+            }
+        }
+
+        let server = self.server.read().clone();
+        let Some(server) = server.filter(|_| get_config().hub) else {
+            return self.write_packets(pkts);
+        };
+
+        let mut local = Vec::new();
+        let mut forward = Vec::new();
+        for pkt in pkts {
+            let (src, dst) = (pkt.source_ip(), pkt.destination_ip());
+            let target = self
+                .routes
+                .lookup(dst, pkt.flow_hash())
+                .and_then(|(_, hop)| server.get_conn_by_addr(&hop))
+                .filter(|target| !target.is_closed() && !Arc::ptr_eq(target, from));
+
+            match target {
+                Some(target) if acl::is_allowed(&get_config().hub_allow, src, dst) => {
+                    forward.push((target, pkt));
+                }
+                Some(_) => debug!(src = %src, dst = %dst, "Hub packet denied by ACL"),
+                None => local.push(pkt),
+            }
+        }
+
+        if !forward.is_empty() {
+            tokio::spawn(async move {
+                for (target, pkt) in &forward {
+                    target.send_packet(pkt).await;
+                }
+            });
+        }
+        if !local.is_empty() {
+            self.write_packets(local);
+        }
+    }
+
+    /// Write received IP packets to the TUN interface, in order
+    fn write_packets(&self, pkts: Vec<PacketIP>) {
+        for pkt in &pkts {
             debug!(
                 pkt_len = pkt.len(),
                 src = %pkt.source_ip(),
                 dst = %pkt.destination_ip(),
                 "Received protobuf packet"
             );
+        }
 
-            // Write to TUN interface
-            let iface_opt = self.iface.read().clone();
-            if let Some(iface) = iface_opt {
-                tokio::spawn(async move {
-                    let mut iface = iface.lock().await;
-                    if let Err(e) = iface.write(&pkt).await {
+        // Write to TUN interface
+        let iface_opt = self.iface.read().clone();
+        if let Some(iface) = iface_opt {
+            tokio::spawn(async move {
+                for pkt in &pkts {
+                    if let Err(e) = iface.write(pkt).await {
                         error!(error = %e, "Failed to write to TUN interface");
                     }
-                });
+                }
+            });
+        }
+    }
+}
+
+impl TransportHandler for AppHandler {
+    fn client_on_data(&self, data: Vec<u8>) {
+        let env = match Envelope::decode(data.as_slice()) {
+            Ok(e) => e,
+            Err(e) => {
+                error!(error = %e, "Failed to decode envelope");
+                return;
+            }
+        };
+
+        match env.r#type {
+            Some(envelope::Type::Packet(packet)) => {
+                self.write_packets(vec![PacketIP::from_bytes(packet.payload)]);
             }
+            Some(envelope::Type::Batch(batch)) => self.write_packets(
+                batch
+                    .packets
+                    .into_iter()
+                    .map(|packet| PacketIP::from_bytes(packet.payload))
+                    .collect(),
+            ),
+            _ => {}
         }
     }
 
@@ -97,53 +327,71 @@ impl TransportHandler for AppHandler {
         match env.r#type {
             Some(envelope::Type::Ping(ping)) => {
                 // Add route based on ping info
-                let ip = ping.ip.clone();
                 let local_addr = ping.local_addr.clone();
 
-                self.routes
-                    .entry(ip.clone())
-                    .or_insert_with(HashSet::new)
-                    .insert(local_addr.clone());
+                let mut vips = Vec::new();
+                for vip in [&ping.ip, &ping.ip6].into_iter().filter(|vip| !vip.is_empty()) {
+                    match vip.parse::<IpAddr>() {
+                        Ok(vip) => vips.push(vip),
+                        Err(e) => warn!(ip = %vip, error = %e, "Invalid virtual IP ignored"),
+                    }
+                }
+
+                if let Some(vip) = vips.iter().find(|vip| !conn.is_ip_allowed(**vip)) {
+                    warn!(ip = %vip, "Virtual IP not allowed for this client, rejecting");
+                    conn.reject("virtual ip not allowed by client certificate or lease");
+                    return;
+                }
+
+                if !self.claim_vips(&vips, &conn) {
+                    return;
+                }
+
+                for vip in &vips {
+                    self.routes.insert(IpNet::from(*vip), local_addr.clone());
+                }
+                let advertised = self.install_advertised_routes(&ping.routes, &conn, &local_addr);
+
+                // Packets from this client must come from what it registered
+                conn.set_sources(vips.iter().copied().map(IpNet::from).chain(advertised).collect());
 
                 debug!(
                     local = %local_addr,
-                    ip = %ip,
+                    ips = ?vips,
                     "Proto Ping"
                 );
 
-                // Log route table
-                let routes: Vec<_> = self.routes.iter()
-                    .map(|r| (r.key().clone(), r.value().clone()))
-                    .collect();
-                info!(route = ?routes, "Route Table");
+                let version = self.routes.version();
+                if self.routes_logged.swap(version, Ordering::Relaxed) != version {
+                    debug!(route = ?self.routes, "Route Table");
+                }
 
                 // Register connection with server
                 if let Some(server) = self.server.read().as_ref() {
                     server.set_conn(local_addr, conn);
                 }
             }
-            Some(envelope::Type::Packet(packet)) => {
-                let pkt = PacketIP::from_bytes(packet.payload);
+            Some(envelope::Type::Packet(packet)) => self.client_packets(vec![packet], &conn),
+            Some(envelope::Type::Batch(batch)) => self.client_packets(batch.packets, &conn),
+            // Stream control messages are handled by the transport
+            _ => {}
+        }
+    }
 
-                debug!(
-                    pkt_len = pkt.len(),
-                    src = %pkt.source_ip(),
-                    dst = %pkt.destination_ip(),
-                    "Received protobuf packet"
-                );
+    fn server_on_lease(&self, identity: &str) -> Option<MessageLease> {
+        let Some(pool) = &self.pool else {
+            return Some(MessageLease::default());
+        };
+        let lease = pool.acquire(identity);
+        if lease.is_none() {
+            warn!(identity = %identity, "Address pool exhausted, rejecting client");
+        }
+        lease
+    }
 
-                // Write to TUN interface
-                let iface_opt = self.iface.read().clone();
-                if let Some(iface) = iface_opt {
-                    tokio::spawn(async move {
-                        let mut iface = iface.lock().await;
-                        if let Err(e) = iface.write(&pkt).await {
-                            error!(error = %e, "Failed to write to TUN interface");
-                        }
-                    });
-                }
-            }
-            None => {}
+    fn server_on_release(&self, identity: &str) {
+        if let Some(pool) = &self.pool {
+            pool.release(identity);
         }
     }
 }
@@ -151,17 +399,32 @@ impl TransportHandler for AppHandler {
 impl App {
     pub fn new() -> Self {
         Self {
-            routes: Arc::new(DashMap::new()),
+            routes: Arc::new(RouteTable::new()),
             server: None,
             client: None,
             iface: None,
+            nat: None,
+            ip: String::new(),
+            ip6: None,
             timer: Timer::new(),
         }
     }
 
     pub async fn run(&mut self) -> anyhow::Result<()> {
         let config = get_config();
-        let handler = Arc::new(AppHandler::new(self.routes.clone()));
+        self.ip = config.ip.clone();
+        self.ip6 = config.ip6.clone();
+
+        let pool = match config.ip_pool {
+            Some(range) if config.server_mode => {
+                let subnet6 = config.ip6.as_deref().map(str::parse).transpose()?;
+                let pool = AddressPool::new(range, config.ip.parse()?, subnet6, &config.ip_reservations)?;
+                info!(pool = %range, reservations = config.ip_reservations.len(), "Address pool enabled");
+                Some(pool)
+            }
+            _ => None,
+        };
+        let handler = Arc::new(AppHandler::new(self.routes.clone(), pool));
 
         if config.server_mode {
             // Server mode
@@ -180,7 +443,11 @@ impl App {
             });
             
             self.server = Some(server);
-            self.start_clean_route();
+            self.start_clean_route(handler.clone());
+
+            if let Some(out_iface) = &config.egress_nat {
+                self.nat = Some(EgressNat::enable(out_iface, &self.subnets()?).await?);
+            }
         } else {
             // Client mode
             let mut client = Client::new(
@@ -190,14 +457,50 @@ impl App {
                 handler.clone(),
             );
             client.start().await?;
-            self.client = Some(Arc::new(TokioMutex::new(client)));
+
+            if config.dynamic_ip {
+                info!("Waiting for the server to lease an address");
+                let lease = tokio::select! {
+                    lease = client.wait_lease() => lease,
+                    e = client.wait_fatal() => return Err(e),
+                };
+                if lease.ip.is_empty() {
+                    anyhow::bail!("Server has no address pool, use --ip instead of --dynamic-ip");
+                }
+                info!(ip = %lease.ip, ip6 = %lease.ip6, "Address leased");
+                self.ip = lease.ip;
+                self.ip6 = Some(lease.ip6).filter(|ip6| !ip6.is_empty());
+            }
+
+            self.client = Some(Arc::new(client));
             self.set_proxy();
         }
 
-        self.start_tun_interface(handler).await
+        // A client that gave up on the server stops the app
+        let client = self.client.clone();
+        let fatal = async {
+            match &client {
+                Some(client) => client.wait_fatal().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            result = self.start_tun_interface(handler) => result,
+            e = fatal => Err(e),
+        }
+    }
+
+    /// Undo the system changes made by `run`
+    pub async fn shutdown(&mut self) {
+        if let Some(iface) = &self.iface {
+            iface.teardown().await;
+        }
+        if let Some(nat) = self.nat.take() {
+            nat.disable().await;
+        }
     }
 
-    fn start_clean_route(&mut self) {
+    fn start_clean_route(&mut self, handler: Arc<AppHandler>) {
         let routes = self.routes.clone();
         let server = self.server.clone();
 
@@ -205,18 +508,16 @@ impl App {
             move || {
                 info!("Starting to clean route");
                 
-                let mut to_remove: Vec<(String, String)> = Vec::new();
-                
-                for entry in routes.iter() {
-                    let dst = entry.key().clone();
-                    for conn_addr in entry.value().iter() {
+                let mut to_remove = Vec::new();
+
+                for (net, hops) in routes.snapshot() {
+                    for conn_addr in hops {
                         if let Some(server) = &server {
-                            if let Some(conn) = server.get_conn_by_addr(conn_addr) {
-                                if conn.is_closed() {
-                                    to_remove.push((dst.clone(), conn_addr.clone()));
-                                }
-                            } else {
-                                to_remove.push((dst.clone(), conn_addr.clone()));
+                            let dead = server
+                                .get_conn_by_addr(&conn_addr)
+                                .is_none_or(|conn| conn.is_closed());
+                            if dead {
+                                to_remove.push((net, conn_addr));
                             }
                         }
                     }
@@ -228,13 +529,12 @@ impl App {
                         dst = %dst,
                         "Removing dead conn from route"
                     );
-                    if let Some(mut conns) = routes.get_mut(&dst) {
-                        conns.remove(&conn_addr);
-                    }
+                    handler.remove_route(&dst, &conn_addr);
                     if let Some(server) = &server {
                         server.delete_dead_conn(&conn_addr);
                     }
                 }
+                handler.prune_owners();
             },
             Duration::from_secs(60),
         );
@@ -243,47 +543,107 @@ impl App {
 
     async fn start_tun_interface(&mut self, handler: Arc<AppHandler>) -> anyhow::Result<()> {
         let config = get_config();
-        let mut iface = Iface::new("", &config.ip, config.mtu);
-        iface.start().await?;
-
-        let iface = Arc::new(TokioMutex::new(iface));
-        handler.set_iface(iface.clone());
-        self.iface = Some(iface.clone());
 
         // Calculate number of workers
         let num_cpus = num_cpus::get();
         let num_workers = (num_cpus * 2).clamp(4, 32);
 
+        let queues = if config.multi_queue { num_workers } else { 1 };
+        let mut iface = Iface::new("", &self.ip, self.ip6.as_deref(), config.mtu, queues);
+        iface.start().await?;
+
+        let (readers, writer) = iface.split()?;
+        let iface = Arc::new(iface);
+        handler.set_iface(iface.clone(), writer);
+        self.iface = Some(iface.clone());
+
+        if config.full_tunnel && !config.server_mode {
+            // Keep the tunnel itself off the tunnel
+            let mut bypass = config.bypass_routes.clone();
+            bypass.extend(
+                tokio::net::lookup_host(&config.remote_addrs)
+                    .await?
+                    .map(|addr| IpNet::from(addr.ip())),
+            );
+            iface.route_all(&bypass).await?;
+        }
+
+        let split = match &config.split_routes {
+            Some(path) if !config.server_mode => Some(self.start_split_routes(path, iface)?),
+            _ => None,
+        };
+
         info!(
             num_workers = num_workers,
             num_cpu = num_cpus,
+            queues = readers.len(),
             "Starting TUN packet workers"
         );
 
-        // Spawn workers
+        // Spawn workers, each reading its own queue when there are enough
         for i in 0..num_workers - 1 {
-            let iface = iface.clone();
-            let routes = self.routes.clone();
+            let reader = readers[i % readers.len()].clone();
+            let handler = handler.clone();
             let server = self.server.clone();
             let client = self.client.clone();
+            let split = split.clone();
             
             tokio::spawn(async move {
-                fetch_and_process_tun_pkt(i, iface, routes, server, client).await;
+                fetch_and_process_tun_pkt(i, reader, handler, server, client, split).await;
             });
         }
 
         // Run last worker in current task
         fetch_and_process_tun_pkt(
             num_workers - 1,
-            iface,
-            self.routes.clone(),
+            readers[(num_workers - 1) % readers.len()].clone(),
+            handler,
             self.server.clone(),
             self.client.clone(),
+            split,
         ).await;
 
         Ok(())
     }
 
+    /// Virtual subnets of the interface
+    fn subnets(&self) -> anyhow::Result<Vec<IpNet>> {
+        let mut subnets = vec![self.ip.parse()?];
+        if let Some(ip6) = &self.ip6 {
+            subnets.push(ip6.parse()?);
+        }
+        Ok(subnets)
+    }
+
+    /// Load the split route list, route its subnets through the TUN and
+    /// keep both in sync as the file changes
+    fn start_split_routes(&mut self, path: &str, iface: Arc<Iface>) -> anyhow::Result<Arc<SplitRoutes>> {
+        let split = Arc::new(SplitRoutes::new(path, &self.subnets()?));
+        let changes = split.reload()?;
+        info!(path = %path, routes = changes.added.len(), "Split routes loaded");
+        update_split_routes(&iface, changes);
+
+        let split_clone = split.clone();
+        self.timer.register_task(
+            move || match split_clone.reload() {
+                Ok(changes) => {
+                    if !changes.added.is_empty() || !changes.removed.is_empty() {
+                        info!(
+                            added = ?changes.added,
+                            removed = ?changes.removed,
+                            "Split routes reloaded"
+                        );
+                        update_split_routes(&iface, changes);
+                    }
+                }
+                Err(e) => warn!(error = %e, "Failed to reload split routes, keeping the current list"),
+            },
+            Duration::from_secs(5),
+        );
+        self.timer.start();
+        Ok(split)
+    }
+
     pub fn set_proxy(&self) {
         #[cfg(target_os = "macos")]
         {
@@ -326,31 +686,32 @@ impl Default for App {
 
 async fn fetch_and_process_tun_pkt(
     worker_num: usize,
-    iface: Arc<TokioMutex<Iface>>,
-    routes: Arc<RouteTable>,
+    iface: IfaceReader,
+    handler: Arc<AppHandler>,
     server: Option<Arc<Server<AppHandler>>>,
-    client: Option<Arc<TokioMutex<Client<AppHandler>>>>,
+    client: Option<Arc<Client<AppHandler>>>,
+    split: Option<Arc<SplitRoutes>>,
 ) {
     let config = get_config();
     let mtu = config.mtu;
     let mut pkt = PacketIP::new(mtu);
+    // Packets without a route, logged at most once per interval
+    let mut no_route = 0u64;
+    let mut no_route_logged: Option<Instant> = None;
 
     loop {
         // Read from TUN
-        let n = {
-            let mut iface = iface.lock().await;
-            match iface.read(&mut pkt).await {
-                Ok(n) => n,
-                Err(e) => {
-                    error!(error = %e, "Failed to read from TUN interface");
-                    continue;
-                }
+        let n = match iface.read(&mut pkt).await {
+            Ok(n) => n,
+            Err(e) => {
+                error!(error = %e, "Failed to read from TUN interface");
+                continue;
             }
         };
 
         pkt.truncate(n);
-        let src = pkt.source_ip().to_string();
-        let dst = pkt.destination_ip().to_string();
+        let src = pkt.source_ip();
+        let dst = pkt.destination_ip();
 
         debug!(
             worker = worker_num,
@@ -364,38 +725,11 @@ async fn fetch_and_process_tun_pkt(
             // Server mode: route packet to appropriate client
             if let Some(server) = &server {
                 let mut found = false;
-                
-                if let Some(conns) = routes.get(&dst) {
-                    if conns.is_empty() {
-                        info!(
-                            worker = worker_num,
-                            src = %src,
-                            dst = %dst,
-                            "Has route but no connection, packet dropped"
-                        );
-                        pkt.resize(mtu);
-                        continue;
-                    }
 
-                    // Pick random connection
-                    let keys: Vec<_> = conns.iter().cloned().collect();
-                    let idx = rand::thread_rng().gen_range(0..keys.len());
-                    let conn_addr = &keys[idx];
-
-                    if let Some(conn) = server.get_conn_by_addr(conn_addr) {
-                        if conn.is_closed() {
-                            info!(
-                                worker = worker_num,
-                                src = %src,
-                                dst = %dst,
-                                "Connection closed, removing"
-                            );
-                            drop(conns);
-                            if let Some(mut entry) = routes.get_mut(&dst) {
-                                entry.remove(conn_addr);
-                            }
-                            server.delete_dead_conn(conn_addr);
-                        } else {
+                // Longest prefix match, keeping each flow on one connection
+                if let Some((net, conn_addr)) = handler.routes.lookup(dst, pkt.flow_hash()) {
+                    match server.get_conn_by_addr(&conn_addr) {
+                        Some(conn) if !conn.is_closed() => {
                             debug!(
                                 worker = worker_num,
                                 src = %src,
@@ -406,22 +740,47 @@ async fn fetch_and_process_tun_pkt(
                             conn.send_packet(&pkt).await;
                             found = true;
                         }
+                        // A dead hop would swallow its flows until the
+                        // clean timer runs, drop it so they rehash
+                        _ => {
+                            info!(
+                                worker = worker_num,
+                                src = %src,
+                                dst = %dst,
+                                "Connection closed, removing"
+                            );
+                            handler.remove_route(&net, &conn_addr);
+                            server.delete_dead_conn(&conn_addr);
+                        }
                     }
                 }
 
                 if !found {
-                    info!(
-                        worker = worker_num,
-                        src = %src,
-                        dst = %dst,
-                        "No route, packet dropped"
-                    );
+                    no_route += 1;
+                    if no_route_logged.is_none_or(|at| at.elapsed() >= NO_ROUTE_LOG_INTERVAL) {
+                        no_route_logged = Some(Instant::now());
+                        info!(
+                            worker = worker_num,
+                            src = %src,
+                            dst = %dst,
+                            dropped = no_route,
+                            "No route, packet dropped"
+                        );
+                    } else {
+                        debug!(worker = worker_num, src = %src, dst = %dst, "No route, packet dropped");
+                    }
                 }
             }
+        } else if split.as_ref().is_some_and(|split| !split.contains(dst)) {
+            debug!(
+                worker = worker_num,
+                dst = %dst,
+                "Destination outside split routes, packet dropped"
+            );
         } else {
             // Client mode: send to server
             if let Some(client) = &client {
-                client.lock().await.send_packet(&pkt).await;
+                client.send_packet(&pkt).await;
             }
         }
 
@@ -430,6 +789,31 @@ async fn fetch_and_process_tun_pkt(
     }
 }
 
+/// Unspecified or link-local source, which clients send without spoofing
+fn is_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_unspecified() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unspecified() || ip.is_unicast_link_local(),
+    }
+}
+
+/// Add and remove kernel routes through the TUN for split route changes
+fn update_split_routes(iface: &Arc<Iface>, changes: SplitChanges) {
+    let iface = iface.clone();
+    tokio::spawn(async move {
+        for net in changes.removed {
+            if let Err(e) = iface.delete_route(net).await {
+                warn!(route = %net, error = %e, "Failed to delete split route");
+            }
+        }
+        for net in changes.added {
+            if let Err(e) = iface.add_route(net).await {
+                warn!(route = %net, error = %e, "Failed to add split route");
+            }
+        }
+    });
+}
+
 // Add num_cpus as a helper
 mod num_cpus {
     pub fn get() -> usize {
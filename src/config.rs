@@ -2,31 +2,131 @@
 
 use std::sync::OnceLock;
 
+use ipnet::{IpNet, Ipv4Net};
+
+use crate::acl::AclRule;
+use crate::conflict::ConflictPolicy;
+use crate::pool::Reservation;
+use crate::transport::CipherSuite;
+
 static GLOBAL_CONFIG: OnceLock<Config> = OnceLock::new();
 
+/// Default largest tunnel frame payload, in bytes
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1 << 20;
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub key: String,
+    /// AEAD cipher suites; the client uses the first, the server accepts all
+    pub ciphers: Vec<CipherSuite>,
     pub remote_addrs: String,
     pub listen: String,
     pub transport_threads: usize,
     pub ip: String,
+    /// Optional IPv6 virtual address in CIDR form
+    pub ip6: Option<String>,
+    /// LAN subnets behind this client, advertised to the server
+    pub advertise_routes: Vec<IpNet>,
+    /// Subnets the server accepts from client advertisements
+    pub accept_routes: Vec<IpNet>,
+    /// Route all client traffic through the tunnel
+    pub full_tunnel: bool,
+    /// Subnets kept on the original default gateway in full tunnel mode
+    pub bypass_routes: Vec<IpNet>,
+    /// File listing the only subnets routed through the tunnel
+    pub split_routes: Option<String>,
+    /// Interface to masquerade client traffic out of
+    pub egress_nat: Option<String>,
+    /// Client identity sent in the handshake, keys address reservations
+    pub client_id: String,
+    /// Configure the interface with the address leased by the server
+    pub dynamic_ip: bool,
+    /// Server range to lease client addresses from
+    pub ip_pool: Option<Ipv4Net>,
+    /// Static client addresses by identity
+    pub ip_reservations: Vec<Reservation>,
+    /// What to do when two clients announce the same virtual IP
+    pub ip_conflict: ConflictPolicy,
+    /// Forward packets between clients inside the server
+    pub hub: bool,
+    /// Client subnet pairs allowed to talk in hub mode, empty allows all
+    pub hub_allow: Vec<AclRule>,
+    /// Let proxied streams reach loopback and unspecified addresses
+    pub proxy_allow_loopback: bool,
     pub mtu: usize,
+    /// Open one TUN queue per packet worker (Linux IFF_MULTI_QUEUE)
+    pub multi_queue: bool,
     pub server_mode: bool,
     pub no_delay: bool,
+    /// Send IP packets as QUIC datagrams when the peer supports them
+    pub datagram: bool,
+    /// Largest frame payload accepted or sent, in bytes
+    pub max_frame_size: usize,
+    /// Byte budget for coalescing queued IP packets into one frame, 0 disables
+    pub batch_size: usize,
+    /// How long the writer waits for more packets to fill a batch, in microseconds
+    pub batch_delay_us: u64,
+    /// Server certificate chain (PEM)
+    pub cert: Option<String>,
+    /// Server private key (PEM)
+    pub cert_key: Option<String>,
+    /// CA bundle used by the client to verify the server (PEM)
+    pub ca: Option<String>,
+    /// Pinned SHA-256 fingerprint of the server certificate
+    pub pin_sha256: Option<String>,
+    /// Accept any server certificate when neither `ca` nor `pin_sha256` is set
+    pub insecure: bool,
+    /// Server name expected in the server certificate
+    pub server_name: String,
+    /// CA bundle used by the server to verify client certificates (PEM)
+    pub client_ca: Option<String>,
+    /// Client certificate chain (PEM)
+    pub client_cert: Option<String>,
+    /// Client private key (PEM)
+    pub client_key: Option<String>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             key: "hello-world".to_string(),
+            ciphers: CipherSuite::ALL.to_vec(),
             remote_addrs: "0.0.0.0:8080".to_string(),
             listen: "0.0.0.0:8080".to_string(),
             transport_threads: 1,
             ip: "10.237.0.1/16".to_string(),
+            ip6: None,
+            advertise_routes: Vec::new(),
+            accept_routes: Vec::new(),
+            full_tunnel: false,
+            bypass_routes: Vec::new(),
+            split_routes: None,
+            egress_nat: None,
+            client_id: String::new(),
+            dynamic_ip: false,
+            ip_pool: None,
+            ip_reservations: Vec::new(),
+            ip_conflict: ConflictPolicy::Reject,
+            hub: false,
+            hub_allow: Vec::new(),
+            proxy_allow_loopback: false,
             mtu: 1500,
+            multi_queue: false,
             server_mode: false,
             no_delay: false,
+            datagram: true,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            batch_size: 16384,
+            batch_delay_us: 0,
+            cert: None,
+            cert_key: None,
+            ca: None,
+            pin_sha256: None,
+            insecure: false,
+            server_name: "localhost".to_string(),
+            client_ca: None,
+            client_cert: None,
+            client_key: None,
         }
     }
 }
@@ -0,0 +1,221 @@
+//! Virtual IP ownership on the server
+//!
+//! Transport connections are grouped by client identity: the one sent in
+//! the handshake, or the certificate fingerprint with client auth. Extra
+//! connections of the owning client share its virtual
+//! IPs; another client announcing the same IP is a conflict, resolved by
+//! rejecting the newcomer or by letting it take the address over.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use parking_lot::Mutex;
+
+/// How long a client that lost an address is refused it, so its
+/// remaining connections cannot take it back
+const EVICTED_HOLD: Duration = Duration::from_secs(60);
+
+/// What to do when a second client announces an IP in use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Keep the current owner and reject the newcomer
+    #[default]
+    Reject,
+    /// Hand the address to the newcomer and disconnect the owner
+    Newest,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "newest" => Ok(Self::Newest),
+            _ => Err(format!("Unknown conflict policy {s}, expected reject or newest")),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Reject => "reject",
+            Self::Newest => "newest",
+        })
+    }
+}
+
+/// Outcome of claiming the virtual IPs of a connection
+pub enum Claim<C> {
+    /// The IPs were free or already owned by the same client
+    Granted,
+    /// Another client owns `ip` and keeps it; none of the IPs was claimed
+    Conflict { ip: IpAddr, owner: String },
+    /// IPs were taken from other clients, listed with their previous
+    /// owner, whose connections are returned
+    TakenOver { previous: Vec<(IpAddr, String)>, conns: Vec<Arc<C>> },
+}
+
+struct Owner<C> {
+    identity: String,
+    conns: Vec<Weak<C>>,
+}
+
+/// Owners of the virtual IPs announced by clients
+pub struct VipOwners<C> {
+    owners: Mutex<HashMap<IpAddr, Owner<C>>>,
+    /// Clients that lost an IP to a newer client, with the time
+    evicted: Mutex<HashMap<(IpAddr, String), Instant>>,
+}
+
+impl<C> VipOwners<C> {
+    pub fn new() -> Self {
+        Self {
+            owners: Mutex::new(HashMap::new()),
+            evicted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Claim `ips` for a connection of `identity`, all or none of them.
+    /// Owners whose connections are all gone, as judged by `alive`, give
+    /// their IPs up
+    pub fn claim(
+        &self,
+        ips: &[IpAddr],
+        identity: &str,
+        conn: &Arc<C>,
+        policy: ConflictPolicy,
+        alive: impl Fn(&C) -> bool,
+    ) -> Claim<C> {
+        let mut owners = self.owners.lock();
+        let mut evicted = self.evicted.lock();
+        evicted.retain(|_, at| at.elapsed() < EVICTED_HOLD);
+
+        // Check every IP before claiming any, so a conflict leaves none claimed
+        let mut taken = Vec::new();
+        for ip in ips {
+            let Some(owner) = owners.get_mut(ip) else { continue };
+            owner.conns.retain(|weak| weak.upgrade().is_some_and(|c| alive(&c)));
+            if owner.identity == identity || owner.conns.is_empty() {
+                continue;
+            }
+            if policy == ConflictPolicy::Reject || evicted.contains_key(&(*ip, identity.to_string())) {
+                return Claim::Conflict { ip: *ip, owner: owner.identity.clone() };
+            }
+            taken.push(*ip);
+        }
+
+        let mut previous = Vec::new();
+        let mut conns: Vec<Arc<C>> = Vec::new();
+        for ip in ips {
+            let owner = owners.entry(*ip).or_insert_with(|| Owner { identity: identity.to_string(), conns: Vec::new() });
+            if taken.contains(ip) {
+                for old in owner.conns.drain(..).filter_map(|weak| weak.upgrade()) {
+                    if !conns.iter().any(|c| Arc::ptr_eq(c, &old)) {
+                        conns.push(old);
+                    }
+                }
+                evicted.insert((*ip, owner.identity.clone()), Instant::now());
+                previous.push((*ip, owner.identity.clone()));
+            }
+
+            owner.identity = identity.to_string();
+            if !owner.conns.iter().any(|weak| std::ptr::eq(weak.as_ptr(), Arc::as_ptr(conn))) {
+                owner.conns.push(Arc::downgrade(conn));
+            }
+        }
+
+        if previous.is_empty() {
+            Claim::Granted
+        } else {
+            Claim::TakenOver { previous, conns }
+        }
+    }
+
+    /// Forget IPs whose owner has no live connection left, and expired
+    /// evictions
+    pub fn prune(&self, alive: impl Fn(&C) -> bool) {
+        self.owners
+            .lock()
+            .retain(|_, owner| owner.conns.iter().any(|weak| weak.upgrade().is_some_and(|c| alive(&c))));
+        self.evicted.lock().retain(|_, at| at.elapsed() < EVICTED_HOLD);
+    }
+}
+
+impl<C> Default for VipOwners<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_vip_claims() {
+        let ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let alive = |closed: &AtomicBool| !closed.load(Ordering::Relaxed);
+        let owners = VipOwners::new();
+        let (a1, a2, b) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)), Arc::new(AtomicBool::new(false)));
+
+        // A second transport connection of the same client shares the IP
+        assert!(matches!(owners.claim(&[ip], "a", &a1, ConflictPolicy::Reject, alive), Claim::Granted));
+        assert!(matches!(owners.claim(&[ip], "a", &a2, ConflictPolicy::Reject, alive), Claim::Granted));
+        assert!(matches!(owners.claim(&[ip], "b", &b, ConflictPolicy::Reject, alive), Claim::Conflict { .. }));
+
+        // The newest client takes over and the old one cannot take it back
+        match owners.claim(&[ip], "b", &b, ConflictPolicy::Newest, alive) {
+            Claim::TakenOver { previous, conns } => {
+                assert_eq!(previous, vec![(ip, "a".to_string())]);
+                assert_eq!(conns.len(), 2);
+            }
+            _ => panic!("expected a takeover"),
+        }
+        assert!(matches!(owners.claim(&[ip], "a", &a1, ConflictPolicy::Newest, alive), Claim::Conflict { .. }));
+
+        // An owner without live connections gives the IP up
+        b.store(true, Ordering::Relaxed);
+        assert!(matches!(owners.claim(&[ip], "c", &a1, ConflictPolicy::Reject, alive), Claim::Granted));
+
+        // And is forgotten once pruned
+        a1.store(true, Ordering::Relaxed);
+        owners.prune(alive);
+        assert!(owners.owners.lock().is_empty());
+    }
+
+    #[test]
+    fn test_claim_all_or_none() {
+        let (ip, ip6): (IpAddr, IpAddr) = ("10.0.0.2".parse().unwrap(), "fd00::2".parse().unwrap());
+        let alive = |_: &()| true;
+        let owners = VipOwners::new();
+        let (a, b, c) = (Arc::new(()), Arc::new(()), Arc::new(()));
+
+        // A conflict on the IPv6 address leaves the IPv4 one unclaimed
+        assert!(matches!(owners.claim(&[ip6], "b", &b, ConflictPolicy::Reject, alive), Claim::Granted));
+        match owners.claim(&[ip, ip6], "a", &a, ConflictPolicy::Reject, alive) {
+            Claim::Conflict { ip: conflict, owner } => {
+                assert_eq!(conflict, ip6);
+                assert_eq!(owner, "b");
+            }
+            _ => panic!("expected a conflict"),
+        }
+        assert!(!owners.owners.lock().contains_key(&ip));
+        assert!(matches!(owners.claim(&[ip], "c", &c, ConflictPolicy::Reject, alive), Claim::Granted));
+
+        // Taking over both addresses returns each old connection once
+        assert!(matches!(owners.claim(&[ip, ip6], "c", &c, ConflictPolicy::Newest, alive), Claim::TakenOver { .. }));
+        match owners.claim(&[ip, ip6], "a", &a, ConflictPolicy::Newest, alive) {
+            Claim::TakenOver { previous, conns } => {
+                assert_eq!(previous, vec![(ip, "c".to_string()), (ip6, "c".to_string())]);
+                assert_eq!(conns.len(), 1);
+            }
+            _ => panic!("expected a takeover"),
+        }
+    }
+}
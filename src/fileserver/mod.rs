@@ -14,7 +14,7 @@ pub async fn start(dir: &str, port: u16) {
             info!(dir = %dir, port = port, "Starting file HTTP server");
             
             let app = Router::new()
-                .nest_service("/", ServeDir::new(&dir));
+                .fallback_service(ServeDir::new(&dir));
 
             let addr = SocketAddr::from(([0, 0, 0, 0], port));
             
@@ -1,7 +1,10 @@
 //! TUN interface module
 
+#[cfg(target_os = "linux")]
+pub mod netlink;
+pub mod nat;
 pub mod packet;
 pub mod tun;
 
 pub use packet::PacketIP;
-pub use tun::Iface;
+pub use tun::{Iface, IfaceReader, IfaceWriter};
@@ -0,0 +1,183 @@
+//! Egress NAT for the server, so clients can reach the internet through it
+//!
+//! Enables IP forwarding and masquerades the virtual subnets out of one
+//! interface with a dedicated nftables table, which also accepts their
+//! forwarded traffic. `disable` drops the table and restores the previous
+//! forwarding settings. The sysctl and `nft` calls block, so they run on
+//! the blocking thread pool.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use ipnet::IpNet;
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// nftables table owned by qtun
+const TABLE: &str = "qtun";
+
+const IPV4_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+const IPV6_FORWARD: &str = "/proc/sys/net/ipv6/conf/all/forwarding";
+
+#[derive(Error, Debug)]
+pub enum NatError {
+    #[error("Egress NAT is only supported on Linux")]
+    Unsupported,
+    #[error("Invalid egress interface name {0:?}")]
+    InterfaceName(String),
+    #[error("Failed to set {path}: {source}")]
+    Sysctl { path: &'static str, source: std::io::Error },
+    #[error("Failed to run nft: {0}")]
+    Spawn(std::io::Error),
+    #[error("nft failed: {0}")]
+    Nft(String),
+    #[error("Egress NAT task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// Active egress NAT, undone by `disable`
+pub struct EgressNat {
+    out_iface: String,
+    /// Forwarding sysctls changed, with their previous values
+    forwarding: Vec<(&'static str, String)>,
+}
+
+impl EgressNat {
+    /// Enable forwarding and masquerade `subnets` leaving `out_iface`
+    pub async fn enable(out_iface: &str, subnets: &[IpNet]) -> Result<Self, NatError> {
+        if !cfg!(target_os = "linux") {
+            return Err(NatError::Unsupported);
+        }
+        if out_iface.is_empty() || !out_iface.chars().all(|c| c.is_ascii_alphanumeric() || "-_.@".contains(c)) {
+            return Err(NatError::InterfaceName(out_iface.to_string()));
+        }
+
+        let out_iface = out_iface.to_string();
+        let subnets = subnets.to_vec();
+        tokio::task::spawn_blocking(move || Self::enable_blocking(&out_iface, &subnets)).await?
+    }
+
+    fn enable_blocking(out_iface: &str, subnets: &[IpNet]) -> Result<Self, NatError> {
+        let mut nat = Self { out_iface: out_iface.to_string(), forwarding: Vec::new() };
+        let has_ipv6 = subnets.iter().any(|net| matches!(net, IpNet::V6(_)));
+        let result = [(IPV4_FORWARD, true), (IPV6_FORWARD, has_ipv6)]
+            .into_iter()
+            .filter(|(_, needed)| *needed)
+            .try_for_each(|(path, _)| nat.enable_forwarding(path))
+            .and_then(|_| nft(&ruleset(out_iface, subnets)));
+        if let Err(e) = result {
+            nat.restore_forwarding();
+            return Err(e);
+        }
+        info!(out_iface = %out_iface, subnets = ?subnets, "Egress NAT enabled");
+        Ok(nat)
+    }
+
+    fn enable_forwarding(&mut self, path: &'static str) -> Result<(), NatError> {
+        let sysctl_err = |source| NatError::Sysctl { path, source };
+        let previous = std::fs::read_to_string(path).map_err(sysctl_err)?;
+        if previous.trim() != "1" {
+            std::fs::write(path, "1").map_err(sysctl_err)?;
+            self.forwarding.push((path, previous.trim().to_string()));
+        }
+        Ok(())
+    }
+
+    /// Remove the NAT rules and restore forwarding; failures are logged
+    pub async fn disable(self) {
+        if let Err(e) = tokio::task::spawn_blocking(move || self.disable_blocking()).await {
+            warn!(error = %e, "Failed to remove egress NAT");
+        }
+    }
+
+    fn disable_blocking(mut self) {
+        if let Err(e) = nft(&format!("delete table inet {TABLE}\n")) {
+            warn!(error = %e, "Failed to remove egress NAT rules");
+        }
+        self.restore_forwarding();
+        info!(out_iface = %self.out_iface, "Egress NAT removed");
+    }
+
+    fn restore_forwarding(&mut self) {
+        for (path, previous) in self.forwarding.drain(..) {
+            if let Err(e) = std::fs::write(path, &previous) {
+                warn!(path = %path, error = %e, "Failed to restore forwarding");
+            }
+        }
+    }
+}
+
+/// nftables script replacing the qtun table, leftovers included.
+///
+/// The forward chain accepts traffic from the subnets and replies to it.
+/// An accept only ends this table's chain: a drop in another table's
+/// forward chain (iptables-nft, Docker, ufw) still applies
+fn ruleset(out_iface: &str, subnets: &[IpNet]) -> String {
+    let family = |net: &IpNet| match net {
+        IpNet::V4(_) => "ip",
+        IpNet::V6(_) => "ip6",
+    };
+    let mut script = format!(
+        "add table inet {TABLE}\n\
+         delete table inet {TABLE}\n\
+         table inet {TABLE} {{\n\
+         \tchain forward {{\n\
+         \t\ttype filter hook forward priority filter; policy accept;\n"
+    );
+    for net in subnets {
+        let (family, net) = (family(net), net.trunc());
+        script.push_str(&format!(
+            "\t\t{family} saddr {net} accept\n\
+             \t\t{family} daddr {net} ct state established,related accept\n"
+        ));
+    }
+    script.push_str(
+        "\t}\n\
+         \tchain postrouting {\n\
+         \t\ttype nat hook postrouting priority srcnat; policy accept;\n",
+    );
+    for net in subnets {
+        script.push_str(&format!(
+            "\t\t{} saddr {} oifname \"{out_iface}\" masquerade\n",
+            family(net),
+            net.trunc()
+        ));
+    }
+    script.push_str("\t}\n}\n");
+    script
+}
+
+/// Run an nftables script
+fn nft(script: &str) -> Result<(), NatError> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(NatError::Spawn)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(script.as_bytes()).map_err(NatError::Spawn)?;
+    }
+
+    let output = child.wait_with_output().map_err(NatError::Spawn)?;
+    if !output.status.success() {
+        return Err(NatError::Nft(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruleset() {
+        let subnets = ["10.237.0.1/16".parse().unwrap(), "fd00:237::1/64".parse().unwrap()];
+        let script = ruleset("eth0", &subnets);
+        assert!(script.starts_with("add table inet qtun\ndelete table inet qtun\n"));
+        assert!(script.contains("ip saddr 10.237.0.0/16 oifname \"eth0\" masquerade"));
+        assert!(script.contains("ip6 saddr fd00:237::/64 oifname \"eth0\" masquerade"));
+        assert!(script.contains("type filter hook forward priority filter; policy accept;"));
+        assert!(script.contains("ip saddr 10.237.0.0/16 accept"));
+        assert!(script.contains("ip6 daddr fd00:237::/64 ct state established,related accept"));
+    }
+}
@@ -0,0 +1,230 @@
+//! Linux interface configuration over rtnetlink
+//!
+//! Everything added through a `Netlink` handle is remembered, so
+//! `teardown` can remove it again when qtun exits.
+
+use std::net::IpAddr;
+use futures::TryStreamExt;
+use ipnet::IpNet;
+use netlink_packet_route::route::Nla;
+use netlink_packet_route::{AddressMessage, RouteMessage, RT_TABLE_MAIN};
+use rtnetlink::{Handle, IpVersion};
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Error, Debug)]
+pub enum NetlinkError {
+    #[error("Failed to open netlink socket: {0}")]
+    Socket(#[from] std::io::Error),
+    #[error("Interface {0} not found")]
+    LinkNotFound(String),
+    #[error("Failed to set interface {link} up: {source}")]
+    Link { link: String, source: rtnetlink::Error },
+    #[error("Failed to add address {addr} to {link}: {source}")]
+    Address { link: String, addr: IpNet, source: rtnetlink::Error },
+    #[error("Failed to add route {dst}: {source}")]
+    Route { dst: IpNet, source: rtnetlink::Error },
+    #[error("Failed to delete route {dst}: {source}")]
+    DeleteRoute { dst: IpNet, source: rtnetlink::Error },
+    #[error("Failed to read routing table: {0}")]
+    Dump(rtnetlink::Error),
+}
+
+/// Next hop of an existing route: an optional gateway on some link
+#[derive(Debug, Clone, Copy)]
+pub struct Gateway {
+    pub addr: Option<IpAddr>,
+    pub index: u32,
+}
+
+/// Changes made so far, undone in reverse order by `teardown`
+#[derive(Default)]
+struct Added {
+    addresses: Vec<AddressMessage>,
+    routes: Vec<(IpNet, RouteMessage)>,
+}
+
+/// Netlink handle bound to one interface
+pub struct Netlink {
+    handle: Handle,
+    name: String,
+    index: u32,
+    added: parking_lot::Mutex<Added>,
+}
+
+impl Netlink {
+    /// Open a netlink connection and look up the interface by name
+    pub async fn connect(name: &str) -> Result<Self, NetlinkError> {
+        let (connection, handle, _) = rtnetlink::new_connection()?;
+        tokio::spawn(connection);
+
+        let link = handle
+            .link()
+            .get()
+            .match_name(name.to_string())
+            .execute()
+            .try_next()
+            .await
+            .ok()
+            .flatten()
+            .ok_or_else(|| NetlinkError::LinkNotFound(name.to_string()))?;
+
+        Ok(Self {
+            handle,
+            name: name.to_string(),
+            index: link.header.index,
+            added: parking_lot::Mutex::new(Added::default()),
+        })
+    }
+
+    /// Interface index
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Set the MTU and bring the link up
+    pub async fn set_up(&self, mtu: u32) -> Result<(), NetlinkError> {
+        self.handle
+            .link()
+            .set(self.index)
+            .mtu(mtu)
+            .up()
+            .execute()
+            .await
+            .map_err(|source| NetlinkError::Link { link: self.name.clone(), source })
+    }
+
+    /// Add an IPv4 or IPv6 address to the link
+    pub async fn add_address(&self, addr: IpNet) -> Result<(), NetlinkError> {
+        let mut request = self.handle.address().add(self.index, addr.addr(), addr.prefix_len());
+        let message = request.message_mut().clone();
+        request.execute().await.map_err(|source| NetlinkError::Address {
+            link: self.name.clone(),
+            addr,
+            source,
+        })?;
+        self.added.lock().addresses.push(message);
+
+        info!(link = %self.name, addr = %addr, "Address added");
+        Ok(())
+    }
+
+    /// Gateway of the main table's default route, preferring the lowest
+    /// metric; None when there is no default route
+    pub async fn default_gateway(&self, ipv6: bool) -> Result<Option<Gateway>, NetlinkError> {
+        let version = if ipv6 { IpVersion::V6 } else { IpVersion::V4 };
+        let mut routes = self.handle.route().get(version).execute();
+
+        let mut best: Option<(u32, Gateway)> = None;
+        while let Some(route) = routes.try_next().await.map_err(NetlinkError::Dump)? {
+            let header = &route.header;
+            if header.destination_prefix_length != 0 || header.table != RT_TABLE_MAIN {
+                continue;
+            }
+            let Some(index) = route.output_interface() else {
+                continue;
+            };
+            let metric = route
+                .nlas
+                .iter()
+                .find_map(|nla| match nla {
+                    Nla::Priority(metric) => Some(*metric),
+                    _ => None,
+                })
+                .unwrap_or(0);
+            if best.is_none_or(|(best_metric, _)| metric < best_metric) {
+                best = Some((metric, Gateway { addr: route.gateway(), index }));
+            }
+        }
+        Ok(best.map(|(_, gateway)| gateway))
+    }
+
+    /// Add a route through this interface, optionally via a gateway
+    pub async fn add_route(&self, dst: IpNet, gateway: Option<IpAddr>) -> Result<(), NetlinkError> {
+        self.add_route_on(self.index, dst, gateway).await
+    }
+
+    /// Add a route through another link, e.g. one kept off the tunnel. A
+    /// route that already exists, left over or added by the user, counts
+    /// as added but is not recorded, so `teardown` leaves it in place
+    pub async fn add_route_via(&self, dst: IpNet, gateway: Gateway) -> Result<(), NetlinkError> {
+        match self.add_route_on(gateway.index, dst, gateway.addr).await {
+            Err(NetlinkError::Route { source: rtnetlink::Error::NetlinkError(e), .. })
+                if e.raw_code().abs() == libc::EEXIST =>
+            {
+                info!(dst = %dst, "Route already exists, leaving it in place");
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn add_route_on(&self, index: u32, dst: IpNet, gateway: Option<IpAddr>) -> Result<(), NetlinkError> {
+        let request = self.handle.route().add().output_interface(index);
+        let message = match (dst, gateway) {
+            (IpNet::V4(net), gateway) => {
+                let mut request = request.v4().destination_prefix(net.network(), net.prefix_len());
+                if let Some(IpAddr::V4(gateway)) = gateway {
+                    request = request.gateway(gateway);
+                }
+                let message = request.message_mut().clone();
+                request.execute().await.map(|_| message)
+            }
+            (IpNet::V6(net), gateway) => {
+                let mut request = request.v6().destination_prefix(net.network(), net.prefix_len());
+                if let Some(IpAddr::V6(gateway)) = gateway {
+                    request = request.gateway(gateway);
+                }
+                let message = request.message_mut().clone();
+                request.execute().await.map(|_| message)
+            }
+        }
+        .map_err(|source| NetlinkError::Route { dst, source })?;
+
+        self.added.lock().routes.push((dst, message));
+        info!(link = %self.name, dst = %dst, "Route added");
+        Ok(())
+    }
+
+    /// Delete a route added through this handle; other routes are left alone
+    pub async fn delete_route(&self, dst: IpNet) -> Result<(), NetlinkError> {
+        let message = {
+            let mut added = self.added.lock();
+            match added.routes.iter().position(|(net, _)| *net == dst) {
+                Some(i) => added.routes.remove(i).1,
+                None => return Ok(()),
+            }
+        };
+
+        self.handle
+            .route()
+            .del(message)
+            .execute()
+            .await
+            .map_err(|source| NetlinkError::DeleteRoute { dst, source })?;
+        info!(link = %self.name, dst = %dst, "Route deleted");
+        Ok(())
+    }
+
+    /// Remove every route and address added through this handle and set
+    /// the link down; failures are logged, not returned
+    pub async fn teardown(&self) {
+        let added = std::mem::take(&mut *self.added.lock());
+
+        for (_, route) in added.routes.into_iter().rev() {
+            if let Err(e) = self.handle.route().del(route).execute().await {
+                warn!(link = %self.name, error = %e, "Failed to delete route");
+            }
+        }
+        for address in added.addresses.into_iter().rev() {
+            if let Err(e) = self.handle.address().del(address).execute().await {
+                warn!(link = %self.name, error = %e, "Failed to delete address");
+            }
+        }
+        if let Err(e) = self.handle.link().set(self.index).down().execute().await {
+            warn!(link = %self.name, error = %e, "Failed to set interface down");
+        }
+
+        info!(link = %self.name, "Interface configuration removed");
+    }
+}
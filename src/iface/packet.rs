@@ -1,6 +1,8 @@
 //! IP Packet handling
 
-use std::net::Ipv4Addr;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 /// IP Packet wrapper
 #[derive(Debug, Clone)]
@@ -21,24 +23,75 @@ impl PacketIP {
         Self { data }
     }
 
-    /// Get the source IP address (IPv4)
-    pub fn source_ip(&self) -> Ipv4Addr {
-        if self.data.len() >= 16 {
-            Ipv4Addr::new(self.data[12], self.data[13], self.data[14], self.data[15])
-        } else {
-            Ipv4Addr::new(0, 0, 0, 0)
+    /// IP version from the first header nibble
+    pub fn version(&self) -> u8 {
+        self.data.first().map_or(0, |byte| byte >> 4)
+    }
+
+    /// Read an address at the given offset for the packet's IP version
+    fn address_at(&self, v4_offset: usize, v6_offset: usize) -> IpAddr {
+        match self.version() {
+            4 if self.data.len() >= v4_offset + 4 => {
+                let octets: [u8; 4] = self.data[v4_offset..v4_offset + 4].try_into().unwrap();
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            6 if self.data.len() >= v6_offset + 16 => {
+                let octets: [u8; 16] = self.data[v6_offset..v6_offset + 16].try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
         }
     }
 
-    /// Get the destination IP address (IPv4)
-    pub fn destination_ip(&self) -> Ipv4Addr {
-        if self.data.len() >= 20 {
-            Ipv4Addr::new(self.data[16], self.data[17], self.data[18], self.data[19])
-        } else {
-            Ipv4Addr::new(0, 0, 0, 0)
+    /// Get the source IP address, unspecified for malformed packets
+    pub fn source_ip(&self) -> IpAddr {
+        self.address_at(12, 8)
+    }
+
+    /// Get the destination IP address, unspecified for malformed packets
+    pub fn destination_ip(&self) -> IpAddr {
+        self.address_at(16, 24)
+    }
+
+    /// Transport protocol and the offset of its header, if known. IPv6
+    /// extension headers and IPv4 fragments are not followed
+    fn transport(&self) -> Option<(u8, usize)> {
+        let data = &self.data;
+        match self.version() {
+            4 if data.len() >= 20 => {
+                let fragmented = u16::from_be_bytes([data[6], data[7]]) & 0x3fff != 0;
+                (!fragmented).then_some((data[9], (data[0] & 0x0f) as usize * 4))
+            }
+            6 if data.len() >= 40 => Some((data[6], 40)),
+            _ => None,
         }
     }
 
+    /// Hash of the flow 5-tuple, the same for both directions of a flow.
+    /// Fragments and protocols without ports hash on addresses only
+    pub fn flow_hash(&self) -> u64 {
+        let data = &self.data;
+        let protocol = match self.version() {
+            4 if data.len() >= 20 => data[9],
+            6 if data.len() >= 40 => data[6],
+            _ => return 0,
+        };
+
+        let mut src = (self.source_ip(), 0u16);
+        let mut dst = (self.destination_ip(), 0u16);
+        // TCP and UDP carry ports in their first four bytes
+        if let Some((6 | 17, offset)) = self.transport() {
+            if data.len() >= offset + 4 {
+                src.1 = u16::from_be_bytes([data[offset], data[offset + 1]]);
+                dst.1 = u16::from_be_bytes([data[offset + 2], data[offset + 3]]);
+            }
+        }
+
+        let mut hasher = DefaultHasher::new();
+        (protocol, src.min(dst), src.max(dst)).hash(&mut hasher);
+        hasher.finish()
+    }
+
     /// Get the underlying bytes
     pub fn as_bytes(&self) -> &[u8] {
         &self.data
@@ -107,6 +160,7 @@ mod tests {
     #[test]
     fn test_packet_ip() {
         let mut pkt = PacketIP::new(20);
+        pkt.as_bytes_mut()[0] = 0x45;
         // Set source IP at offset 12-15
         pkt.as_bytes_mut()[12] = 192;
         pkt.as_bytes_mut()[13] = 168;
@@ -121,4 +175,40 @@ mod tests {
         assert_eq!(pkt.source_ip(), Ipv4Addr::new(192, 168, 1, 1));
         assert_eq!(pkt.destination_ip(), Ipv4Addr::new(10, 0, 0, 1));
     }
+
+    #[test]
+    fn test_packet_ipv6() {
+        let src: Ipv6Addr = "fd00::2".parse().unwrap();
+        let dst: Ipv6Addr = "fd00::1".parse().unwrap();
+        let mut data = vec![0u8; 40];
+        data[0] = 0x60;
+        data[8..24].copy_from_slice(&src.octets());
+        data[24..40].copy_from_slice(&dst.octets());
+        let pkt = PacketIP::from_bytes(data);
+
+        assert_eq!(pkt.version(), 6);
+        assert_eq!(pkt.source_ip(), src);
+        assert_eq!(pkt.destination_ip(), dst);
+        assert_eq!(PacketIP::new(4).destination_ip(), Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn test_flow_hash() {
+        let udp = |src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16| {
+            let mut data = vec![0u8; 28];
+            data[0] = 0x45;
+            data[9] = 17;
+            data[12..16].copy_from_slice(&src);
+            data[16..20].copy_from_slice(&dst);
+            data[20..22].copy_from_slice(&sport.to_be_bytes());
+            data[22..24].copy_from_slice(&dport.to_be_bytes());
+            PacketIP::from_bytes(data)
+        };
+
+        let out = udp([10, 0, 0, 1], [10, 0, 0, 2], 5000, 53);
+        let back = udp([10, 0, 0, 2], [10, 0, 0, 1], 53, 5000);
+        let other = udp([10, 0, 0, 1], [10, 0, 0, 2], 5001, 53);
+        assert_eq!(out.flow_hash(), back.flow_hash());
+        assert_ne!(out.flow_hash(), other.flow_hash());
+    }
 }
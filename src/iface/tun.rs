@@ -1,34 +1,70 @@
 //! TUN interface implementation
 
+#[cfg(target_os = "linux")]
+use std::ffi::CStr;
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd};
+#[cfg(target_os = "macos")]
 use std::process::Command;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::sync::Arc;
 use anyhow::Result;
-use ipnet::Ipv4Net;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::{debug, error, info};
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+use tracing::info;
+#[cfg(target_os = "macos")]
+use tracing::{debug, error};
 
+#[cfg(target_os = "linux")]
+use super::netlink::Netlink;
 use super::PacketIP;
 
-#[cfg(target_os = "macos")]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
 use tun2::AbstractDevice;
 
 /// TUN interface wrapper
 pub struct Iface {
     name: String,
     ip: String,
+    /// Optional IPv6 address in CIDR form
+    ip6: Option<String>,
     mtu: usize,
+    /// Number of TUN queues, more than one needs IFF_MULTI_QUEUE (Linux)
+    queues: usize,
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    devices: Vec<Arc<tun2::AsyncDevice>>,
+    /// Addresses and routes are managed over rtnetlink on Linux
+    #[cfg(target_os = "linux")]
+    netlink: Option<Arc<Netlink>>,
+}
+
+/// Reading half of one TUN queue; clones read concurrently
+#[derive(Clone)]
+pub struct IfaceReader {
     #[cfg(any(target_os = "linux", target_os = "macos"))]
-    device: Option<tun2::AsyncDevice>,
+    device: Arc<tun2::AsyncDevice>,
+}
+
+/// Writing half of a TUN interface, pinning each flow to one queue;
+/// clones write concurrently
+#[derive(Clone)]
+pub struct IfaceWriter {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    queues: Vec<Arc<tun2::AsyncDevice>>,
 }
 
 impl Iface {
     /// Create a new TUN interface
-    pub fn new(name: &str, ip: &str, mtu: usize) -> Self {
+    pub fn new(name: &str, ip: &str, ip6: Option<&str>, mtu: usize, queues: usize) -> Self {
         Self {
             name: name.to_string(),
             ip: ip.to_string(),
+            ip6: ip6.map(str::to_string),
             mtu,
+            queues: queues.max(1),
             #[cfg(any(target_os = "linux", target_os = "macos"))]
-            device: None,
+            devices: Vec::new(),
+            #[cfg(target_os = "linux")]
+            netlink: None,
         }
     }
 
@@ -37,40 +73,69 @@ impl Iface {
     pub async fn start(&mut self) -> Result<()> {
         // Parse IP/CIDR
         let network: Ipv4Net = self.ip.parse()?;
-        let ip = network.addr();
-        let netmask = network.netmask();
+        let network6: Option<Ipv6Net> = self.ip6.as_deref().map(str::parse).transpose()?;
+        #[cfg(target_os = "macos")]
+        let (ip, netmask) = (network.addr(), network.netmask());
 
-        // Create TUN config
+        // Create TUN config; on Linux the link is configured over netlink
         let mut config = tun2::Configuration::default();
+        config.mtu(self.mtu as u16);
+        #[cfg(target_os = "macos")]
         config.address(ip)
               .netmask(netmask)
-              .mtu(self.mtu as u16)
               .up();
 
+        // Create TUN device, with one fd per queue when multi-queue
         #[cfg(target_os = "linux")]
-        config.platform_config(|config| {
-            config.ensure_root_privileges(true);
-        });
+        let (devices, tun_name) = if self.queues > 1 {
+            let (first, tun_name) = open_queue(&self.name, self.mtu)?;
+            let mut devices = vec![first];
+            for _ in 1..self.queues {
+                devices.push(open_queue(&tun_name, self.mtu)?.0);
+            }
+            (devices, tun_name)
+        } else {
+            let device = tun2::create_as_async(&config)?;
+            let tun_name = device.tun_name().unwrap_or_else(|_| "tun0".to_string());
+            (vec![device], tun_name)
+        };
 
-        // Create TUN device
-        let device = tun2::create_as_async(&config)?;
-        
         #[cfg(target_os = "macos")]
-        let tun_name = device.tun_name()?;
-        #[cfg(target_os = "linux")]
-        let tun_name = device.tun_name().unwrap_or_else(|_| "tun0".to_string());
+        let (devices, tun_name) = {
+            if self.queues > 1 {
+                tracing::warn!("Multi-queue TUN is only supported on Linux, using one queue");
+            }
+            let device = tun2::create_as_async(&config)?;
+            let tun_name = device.tun_name()?;
+            (vec![device], tun_name)
+        };
         
         self.name = tun_name.clone();
         
-        info!(tun_name = %tun_name, "TUN interface created");
+        info!(tun_name = %tun_name, queues = devices.len(), "TUN interface created");
 
-        // Configure interface using system commands
-        self.configure_interface(&ip.to_string(), &netmask.to_string())?;
+        #[cfg(target_os = "linux")]
+        {
+            let netlink = Netlink::connect(&tun_name).await?;
+            netlink.set_up(self.mtu as u32).await?;
+            netlink.add_address(network.into()).await?;
+            if let Some(network6) = network6 {
+                netlink.add_address(network6.into()).await?;
+            }
+            self.netlink = Some(Arc::new(netlink));
+        }
 
+        // Configure interface using system commands
         #[cfg(target_os = "macos")]
-        self.add_system_route(&ip.to_string())?;
+        {
+            self.configure_interface(&ip.to_string(), &netmask.to_string())?;
+            self.add_system_route(&network)?;
+            if let Some(network6) = network6 {
+                self.configure_ipv6(&network6)?;
+            }
+        }
 
-        self.device = Some(device);
+        self.devices = devices.into_iter().map(Arc::new).collect();
         Ok(())
     }
 
@@ -79,66 +144,60 @@ impl Iface {
         anyhow::bail!("TUN interface not supported on this platform")
     }
 
+    #[cfg(target_os = "macos")]
     fn configure_interface(&self, ip: &str, netmask: &str) -> Result<()> {
-        #[cfg(target_os = "macos")]
-        {
-            let output = Command::new("ifconfig")
-                .args([
-                    &self.name,
-                    ip,
-                    ip,
-                    "netmask",
-                    netmask,
-                    "mtu",
-                    &self.mtu.to_string(),
-                    "up",
-                ])
-                .output()?;
-
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                error!(cmd_output = %stderr, "Run ifconfig fail");
-                anyhow::bail!("Failed to configure interface: {}", stderr);
-            }
-        }
+        let output = Command::new("ifconfig")
+            .args([
+                &self.name,
+                ip,
+                ip,
+                "netmask",
+                netmask,
+                "mtu",
+                &self.mtu.to_string(),
+                "up",
+            ])
+            .output()?;
 
-        #[cfg(target_os = "linux")]
-        {
-            let output = Command::new("ifconfig")
-                .args([
-                    &self.name,
-                    ip,
-                    "netmask",
-                    netmask,
-                    "mtu",
-                    &self.mtu.to_string(),
-                    "up",
-                ])
-                .output()?;
-
-            if !output.status.success() {
-                let stderr = String::from_utf8_lossy(&output.stderr);
-                error!(cmd_output = %stderr, "Run ifconfig fail");
-                anyhow::bail!("Failed to configure interface: {}", stderr);
-            }
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            error!(cmd_output = %stderr, "Run ifconfig fail");
+            anyhow::bail!("Failed to configure interface: {}", stderr);
         }
 
         Ok(())
     }
 
     #[cfg(target_os = "macos")]
-    fn add_system_route(&self, ip: &str) -> Result<()> {
-        // Calculate subnet from IP (e.g., 10.4.4.3 -> 10.4.4.0)
-        let parts: Vec<&str> = ip.split('.').collect();
-        if parts.len() != 4 {
-            anyhow::bail!("Invalid IP address format");
+    fn configure_ipv6(&self, network: &Ipv6Net) -> Result<()> {
+        let output = Command::new("ifconfig")
+            .args([
+                &self.name,
+                "inet6",
+                &network.addr().to_string(),
+                "prefixlen",
+                &network.prefix_len().to_string(),
+                "alias",
+            ])
+            .output()?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            error!(cmd_output = %stderr, "Run ifconfig inet6 fail");
+            anyhow::bail!("Failed to configure IPv6 address: {}", stderr);
         }
-        let subnet = format!("{}.{}.{}.0", parts[0], parts[1], parts[2]);
+
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    fn add_system_route(&self, network: &Ipv4Net) -> Result<()> {
+        let subnet = network.trunc().to_string();
         
         debug!(subnet = %subnet, "Adding route");
 
         let output = Command::new("route")
-            .args(["add", "-net", &subnet, ip])
+            .args(["add", "-net", &subnet, &network.addr().to_string()])
             .output()?;
 
         if !output.status.success() {
@@ -158,35 +217,177 @@ impl Iface {
         &self.name
     }
 
-    /// Read a packet from the TUN interface
+    /// Route a destination through the interface
+    #[cfg(target_os = "linux")]
+    pub async fn add_route(&self, dst: IpNet) -> Result<()> {
+        match &self.netlink {
+            Some(netlink) => Ok(netlink.add_route(dst, None).await?),
+            None => anyhow::bail!("TUN device not initialized"),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub async fn add_route(&self, _dst: IpNet) -> Result<()> {
+        anyhow::bail!("Adding routes is only supported on Linux")
+    }
+
+    /// Route all traffic through the interface with two half-default
+    /// routes, keeping `bypass` on the original default gateway. IPv6 is
+    /// only captured when the interface has an IPv6 address
+    #[cfg(target_os = "linux")]
+    pub async fn route_all(&self, bypass: &[IpNet]) -> Result<()> {
+        let Some(netlink) = &self.netlink else {
+            anyhow::bail!("TUN device not initialized");
+        };
+
+        for ipv6 in [false, true] {
+            if ipv6 && self.ip6.is_none() {
+                continue;
+            }
+            let family_bypass = bypass.iter().filter(|net| matches!(net, IpNet::V6(_)) == ipv6);
+            match netlink.default_gateway(ipv6).await? {
+                Some(gateway) => {
+                    for net in family_bypass {
+                        netlink.add_route_via(net.trunc(), gateway).await?;
+                    }
+                }
+                None if !ipv6 => anyhow::bail!("No IPv4 default route, cannot keep the server reachable"),
+                None => {
+                    for net in family_bypass {
+                        tracing::warn!(route = %net, "No IPv6 default route, bypass ignored");
+                    }
+                }
+            }
+
+            let halves: [IpNet; 2] = if ipv6 {
+                ["::/1".parse()?, "8000::/1".parse()?]
+            } else {
+                ["0.0.0.0/1".parse()?, "128.0.0.0/1".parse()?]
+            };
+            for half in halves {
+                netlink.add_route(half, None).await?;
+            }
+        }
+
+        info!(tun_name = %self.name, bypass = ?bypass, "Full tunnel routes installed");
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub async fn route_all(&self, _bypass: &[IpNet]) -> Result<()> {
+        anyhow::bail!("Full tunnel mode is only supported on Linux")
+    }
+
+    /// Delete a route added with `add_route`
+    #[cfg(target_os = "linux")]
+    pub async fn delete_route(&self, dst: IpNet) -> Result<()> {
+        match &self.netlink {
+            Some(netlink) => Ok(netlink.delete_route(dst).await?),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub async fn delete_route(&self, _dst: IpNet) -> Result<()> {
+        Ok(())
+    }
+
+    /// Remove the addresses and routes added to the interface
+    pub async fn teardown(&self) {
+        #[cfg(target_os = "linux")]
+        if let Some(netlink) = &self.netlink {
+            netlink.teardown().await;
+        }
+    }
+
+    /// Split the started interface into one reader per queue and a
+    /// writer shared by all, so reads never wait on writes
     #[cfg(any(target_os = "linux", target_os = "macos"))]
-    pub async fn read(&mut self, pkt: &mut PacketIP) -> Result<usize> {
-        if let Some(device) = &mut self.device {
-            let n = device.read(pkt.as_bytes_mut()).await?;
-            Ok(n)
-        } else {
-            anyhow::bail!("TUN device not initialized")
+    pub fn split(&self) -> Result<(Vec<IfaceReader>, IfaceWriter)> {
+        if self.devices.is_empty() {
+            anyhow::bail!("TUN device not initialized");
         }
+        let readers = self
+            .devices
+            .iter()
+            .map(|device| IfaceReader { device: device.clone() })
+            .collect();
+        Ok((readers, IfaceWriter { queues: self.devices.clone() }))
     }
 
     #[cfg(not(any(target_os = "linux", target_os = "macos")))]
-    pub async fn read(&mut self, _pkt: &mut PacketIP) -> Result<usize> {
+    pub fn split(&self) -> Result<(Vec<IfaceReader>, IfaceWriter)> {
         anyhow::bail!("TUN interface not supported on this platform")
     }
+}
+
+/// Open one queue of a multi-queue TUN device, creating the device when
+/// `name` is empty or new, and return it with the device name
+#[cfg(target_os = "linux")]
+fn open_queue(name: &str, mtu: usize) -> Result<(tun2::AsyncDevice, String)> {
+    // The kernel needs room for the terminating NUL
+    if name.len() > libc::IFNAMSIZ - 1 || name.contains('\0') {
+        anyhow::bail!("Invalid TUN interface name: {}", name);
+    }
+
+    // SAFETY: the path is a NUL-terminated literal and the flags are valid
+    // for open(2); the returned fd is checked before use
+    let fd = unsafe { libc::open(c"/dev/net/tun".as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // SAFETY: fd was just opened, is valid and owned by nobody else
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // SAFETY: ifreq is a plain C struct for which all zero bytes are valid
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    // At most IFNAMSIZ - 1 bytes are copied, the zeroed tail terminates it
+    for (dst, src) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI | libc::IFF_MULTI_QUEUE) as libc::c_short;
+    // SAFETY: TUNSETIFF reads and writes a struct ifreq, and req is a live,
+    // exclusively borrowed ifreq on an open /dev/net/tun fd
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut req) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // SAFETY: the kernel writes back a NUL-terminated name shorter than
+    // IFNAMSIZ, so the pointer stays within ifr_name
+    let name = unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned();
+
+    let mut config = tun2::Configuration::default();
+    config.raw_fd(fd.into_raw_fd()).tun_name(&name).mtu(mtu as u16);
+    Ok((tun2::create_as_async(&config)?, name))
+}
 
-    /// Write a packet to the TUN interface
+impl IfaceReader {
+    /// Read a packet from the TUN interface
     #[cfg(any(target_os = "linux", target_os = "macos"))]
-    pub async fn write(&mut self, pkt: &PacketIP) -> Result<usize> {
-        if let Some(device) = &mut self.device {
-            let n = device.write(pkt.as_bytes()).await?;
-            Ok(n)
-        } else {
-            anyhow::bail!("TUN device not initialized")
-        }
+    pub async fn read(&self, pkt: &mut PacketIP) -> Result<usize> {
+        Ok(self.device.recv(pkt.as_bytes_mut()).await?)
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub async fn read(&self, _pkt: &mut PacketIP) -> Result<usize> {
+        anyhow::bail!("TUN interface not supported on this platform")
+    }
+}
+
+impl IfaceWriter {
+    /// Write a packet to the TUN interface, on the queue of its flow
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub async fn write(&self, pkt: &PacketIP) -> Result<usize> {
+        let queue = match self.queues.len() {
+            1 => &self.queues[0],
+            n => &self.queues[(pkt.flow_hash() % n as u64) as usize],
+        };
+        Ok(queue.send(pkt.as_bytes()).await?)
     }
 
     #[cfg(not(any(target_os = "linux", target_os = "macos")))]
-    pub async fn write(&mut self, _pkt: &PacketIP) -> Result<usize> {
+    pub async fn write(&self, _pkt: &PacketIP) -> Result<usize> {
         anyhow::bail!("TUN interface not supported on this platform")
     }
 }
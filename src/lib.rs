@@ -6,3 +6,8 @@ pub mod socks5;
 pub mod fileserver;
 pub mod app;
 pub mod protocol;
+pub mod route;
+pub mod acl;
+pub mod split;
+pub mod pool;
+pub mod conflict;
@@ -1,13 +1,19 @@
 //! Qtun - A VPN tunnel tool based on QUIC protocol
 
-use clap::Parser;
-use tracing::info;
+use std::io::Write;
+use std::sync::Arc;
+use clap::{Parser, Subcommand};
+use ipnet::{IpNet, Ipv4Net};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+use qtun::acl::AclRule;
 use qtun::app::App;
-use qtun::config::{init_config, Config};
+use qtun::conflict::ConflictPolicy;
+use qtun::config::{get_config, init_config, Config};
 use qtun::fileserver;
+use qtun::pool::Reservation;
 use qtun::socks5;
+use qtun::transport::{tls, CipherSuite, TunnelDialer};
 
 /// Command line options
 #[derive(Parser, Debug)]
@@ -19,6 +25,11 @@ struct CmdOpts {
     #[arg(long, default_value = "hello-world")]
     key: String,
 
+    /// AEAD cipher suites (aes-128-gcm, aes-256-gcm, chacha20-poly1305).
+    /// The client uses the first one, the server accepts all listed
+    #[arg(long, value_delimiter = ',', default_value = "aes-128-gcm,aes-256-gcm,chacha20-poly1305")]
+    ciphers: Vec<CipherSuite>,
+
     /// Remote server address (client only)
     #[arg(long, default_value = "2.2.2.2:8080")]
     remote_addrs: String,
@@ -31,6 +42,66 @@ struct CmdOpts {
     #[arg(long, default_value = "10.237.0.1/16")]
     ip: String,
 
+    /// Optional IPv6 virtual address with prefix, e.g. fd00:237::1/64
+    #[arg(long)]
+    ip6: Option<String>,
+
+    /// LAN subnets behind this client to advertise to the server (client)
+    #[arg(long, value_delimiter = ',')]
+    advertise_routes: Vec<IpNet>,
+
+    /// Subnets the server accepts from client advertisements (server)
+    #[arg(long, value_delimiter = ',')]
+    accept_routes: Vec<IpNet>,
+
+    /// Send all traffic through the tunnel, restored on exit (client, Linux)
+    #[arg(long, default_value = "false")]
+    full_tunnel: bool,
+
+    /// Subnets that skip the tunnel in full tunnel mode (client)
+    #[arg(long, value_delimiter = ',')]
+    bypass_routes: Vec<IpNet>,
+
+    /// File of subnets to send through the tunnel, reloaded on change (client)
+    #[arg(long, conflicts_with = "full_tunnel")]
+    split_routes: Option<String>,
+
+    /// Enable forwarding and masquerade the virtual subnet out of this interface (server, Linux)
+    #[arg(long)]
+    egress_nat: Option<String>,
+
+    /// Client identity for address reservations, random per run if unset (client)
+    #[arg(long)]
+    client_id: Option<String>,
+
+    /// Use the virtual address leased by the server instead of --ip/--ip6 (client)
+    #[arg(long, default_value = "false")]
+    dynamic_ip: bool,
+
+    /// Range to lease client virtual addresses from, inside --ip (server)
+    #[arg(long)]
+    ip_pool: Option<Ipv4Net>,
+
+    /// Static addresses per client identity, e.g. laptop=10.237.0.10 (server)
+    #[arg(long, value_delimiter = ',')]
+    ip_reserve: Vec<Reservation>,
+
+    /// Virtual IP claimed by two clients: reject the newcomer, or let the newest win (server)
+    #[arg(long, default_value = "reject")]
+    ip_conflict: ConflictPolicy,
+
+    /// Forward client-to-client packets inside the server, bypassing the TUN (server)
+    #[arg(long, default_value = "false")]
+    hub: bool,
+
+    /// Subnet pairs allowed to talk in hub mode, e.g. 10.237.1.0/24-10.237.2.0/24 (server)
+    #[arg(long, value_delimiter = ',')]
+    hub_allow: Vec<AclRule>,
+
+    /// Let proxied CONNECT sessions reach loopback and unspecified addresses (server)
+    #[arg(long, default_value = "false")]
+    proxy_allow_loopback: bool,
+
     /// Log level (info, debug)
     #[arg(long, default_value = "info")]
     log_level: String,
@@ -47,6 +118,10 @@ struct CmdOpts {
     #[arg(long, default_value = "1500")]
     mtu: usize,
 
+    /// Open one TUN queue per packet worker (Linux only)
+    #[arg(long, default_value = "false")]
+    multi_queue: bool,
+
     /// SOCKS5 server port
     #[arg(long, default_value = "2080")]
     socks5_port: u16,
@@ -63,9 +138,126 @@ struct CmdOpts {
     #[arg(long, default_value = "false")]
     nodelay: bool,
 
+    /// Send IP packets on the stream only, never as QUIC datagrams
+    #[arg(long, default_value = "false")]
+    no_datagram: bool,
+
+    /// Largest tunnel frame payload accepted or sent, in bytes
+    #[arg(long, default_value = "1048576")]
+    max_frame_size: usize,
+
+    /// Byte budget for batching queued IP packets into one frame, 0 disables
+    #[arg(long, default_value = "16384")]
+    batch_size: usize,
+
+    /// Microseconds the writer waits for more packets to fill a batch
+    #[arg(long, default_value = "0")]
+    batch_delay_us: u64,
+
     /// Only enable proxy (no TUN)
     #[arg(long, default_value = "false")]
     proxyonly: bool,
+
+    /// TLS certificate chain in PEM format (server only)
+    #[arg(long)]
+    cert: Option<String>,
+
+    /// TLS private key in PEM format (server only)
+    #[arg(long)]
+    cert_key: Option<String>,
+
+    /// CA bundle in PEM format to verify the server certificate (client only)
+    #[arg(long)]
+    ca: Option<String>,
+
+    /// SHA-256 fingerprint of the server certificate to pin (client only)
+    #[arg(long)]
+    pin_sha256: Option<String>,
+
+    /// Skip server certificate verification when neither --ca nor --pin-sha256 is set, open to MITM (client only)
+    #[arg(long, default_value = "false")]
+    insecure: bool,
+
+    /// Server name expected in the server certificate
+    #[arg(long, default_value = "localhost")]
+    server_name: String,
+
+    /// CA bundle in PEM format to require and verify client certificates (server only)
+    #[arg(long)]
+    client_ca: Option<String>,
+
+    /// Client certificate chain in PEM format (client only)
+    #[arg(long)]
+    client_cert: Option<String>,
+
+    /// Client private key in PEM format (client only)
+    #[arg(long)]
+    client_key: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Generate a persistent server certificate and key, and print its fingerprint
+    GenCert {
+        /// Output path for the certificate
+        #[arg(long, default_value = "qtun-cert.pem")]
+        cert_out: String,
+
+        /// Output path for the private key
+        #[arg(long, default_value = "qtun-key.pem")]
+        key_out: String,
+
+        /// Subject alternative names (DNS names or IPs). For client
+        /// certificates, an IP SAN is the virtual IP the client may use
+        #[arg(long, default_value = "localhost")]
+        san: Vec<String>,
+
+        /// Generate a CA certificate able to sign other certificates
+        #[arg(long, default_value = "false")]
+        is_ca: bool,
+
+        /// CA certificate to sign with (PEM), self-signed if not given
+        #[arg(long, requires = "issuer_key")]
+        issuer_cert: Option<String>,
+
+        /// CA private key to sign with (PEM)
+        #[arg(long, requires = "issuer_cert")]
+        issuer_key: Option<String>,
+    },
+}
+
+fn gen_cert(
+    cert_out: &str,
+    key_out: &str,
+    san: Vec<String>,
+    is_ca: bool,
+    issuer: Option<(String, String)>,
+) -> anyhow::Result<()> {
+    let issuer = match issuer {
+        Some((cert, key)) => Some((std::fs::read_to_string(cert)?, std::fs::read_to_string(key)?)),
+        None => None,
+    };
+    let generated = tls::generate_cert(
+        san,
+        is_ca,
+        issuer.as_ref().map(|(cert, key)| (cert.as_str(), key.as_str())),
+    )?;
+
+    std::fs::write(cert_out, &generated.cert_pem)?;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(key_out)?.write_all(generated.key_pem.as_bytes())?;
+
+    println!("Certificate: {}", cert_out);
+    println!("Private key: {}", key_out);
+    println!("SHA-256 fingerprint: {}", generated.fingerprint);
+    Ok(())
 }
 
 fn init_logging(log_level: &str) {
@@ -90,29 +282,78 @@ fn init_logging(log_level: &str) {
 async fn main() -> anyhow::Result<()> {
     let opts = CmdOpts::parse();
 
+    if let Some(Commands::GenCert { cert_out, key_out, san, is_ca, issuer_cert, issuer_key }) = opts.command {
+        return gen_cert(&cert_out, &key_out, san, is_ca, issuer_cert.zip(issuer_key));
+    }
+
     // Print options
     println!("{:?}", opts);
 
     // Initialize config
     init_config(Config {
         key: opts.key,
+        ciphers: opts.ciphers,
         remote_addrs: opts.remote_addrs,
         listen: opts.listen,
         transport_threads: opts.transport_threads,
         ip: opts.ip,
+        ip6: opts.ip6,
+        advertise_routes: opts.advertise_routes,
+        accept_routes: opts.accept_routes,
+        full_tunnel: opts.full_tunnel,
+        bypass_routes: opts.bypass_routes,
+        split_routes: opts.split_routes,
+        egress_nat: opts.egress_nat,
+        client_id: opts
+            .client_id
+            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>())),
+        dynamic_ip: opts.dynamic_ip,
+        ip_pool: opts.ip_pool,
+        ip_reservations: opts.ip_reserve,
+        ip_conflict: opts.ip_conflict,
+        hub: opts.hub,
+        hub_allow: opts.hub_allow,
+        proxy_allow_loopback: opts.proxy_allow_loopback,
         mtu: opts.mtu,
+        multi_queue: opts.multi_queue,
         server_mode: opts.server_mode,
         no_delay: opts.nodelay,
+        datagram: !opts.no_datagram,
+        max_frame_size: opts.max_frame_size,
+        batch_size: opts.batch_size,
+        batch_delay_us: opts.batch_delay_us,
+        cert: opts.cert,
+        cert_key: opts.cert_key,
+        ca: opts.ca,
+        pin_sha256: opts.pin_sha256,
+        insecure: opts.insecure,
+        server_name: opts.server_name,
+        client_ca: opts.client_ca,
+        client_cert: opts.client_cert,
+        client_key: opts.client_key,
     });
 
     // Initialize logging
     init_logging(&opts.log_level);
 
-    // Proxy only mode
+    // Refuse to start a client that cannot verify the server
+    if !opts.server_mode {
+        tls::client_crypto_config()?;
+    }
+
+    // Proxy only mode: tunnel SOCKS5 CONNECT sessions through the server
     if opts.proxyonly {
-        let mut app = App::new();
+        let config = get_config();
+        let dialer = TunnelDialer::new(config.remote_addrs.clone(), config.key.clone())?;
+        let socks5_config = socks5::Config {
+            dialer: Arc::new(dialer),
+            ..Default::default()
+        };
+
+        let app = App::new();
         app.set_proxy();
-        socks5::start_socks5(&opts.socks5_port.to_string()).await;
+        fileserver::start(&opts.file_dir, opts.file_svr_port).await;
+        socks5::start_socks5(&opts.socks5_port.to_string(), socks5_config).await;
         return Ok(());
     }
 
@@ -121,14 +362,42 @@ async fn main() -> anyhow::Result<()> {
         // Server mode: start SOCKS5 server
         let socks5_port = opts.socks5_port.to_string();
         tokio::spawn(async move {
-            socks5::start_socks5(&socks5_port).await;
+            socks5::start_socks5(&socks5_port, socks5::Config::default()).await;
         });
     } else {
         // Client mode: start file server
         fileserver::start(&opts.file_dir, opts.file_svr_port).await;
     }
 
-    // Run main application
+    // Run main application until interrupted, then undo system changes
     let mut app = App::new();
-    app.run().await
+    let result = tokio::select! {
+        result = app.run() => result,
+        _ = shutdown_signal() => Ok(()),
+    };
+    app.shutdown().await;
+    result
+}
+
+/// Wait for Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = term.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+
+    tracing::info!("Shutting down");
 }
@@ -0,0 +1,209 @@
+//! Virtual address pool for dynamic client addressing
+//!
+//! The server leases one IPv4 address per client identity, plus the IPv6
+//! address at the same host offset when it has an IPv6 subnet. Static
+//! reservations pin an identity to an address. A lease stays with its
+//! identity after the last connection closes and is only reclaimed when
+//! the pool runs out, so reconnecting clients keep their address.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::time::Instant;
+use ipnet::{Ipv4Net, Ipv6Net};
+use parking_lot::Mutex;
+use thiserror::Error;
+
+use crate::protocol::MessageLease;
+
+#[derive(Error, Debug)]
+pub enum PoolError {
+    #[error("Invalid reservation {0}, expected IDENTITY=IP")]
+    Reservation(String),
+    #[error("Address pool {pool} is outside the server subnet {subnet}")]
+    OutsideSubnet { pool: Ipv4Net, subnet: Ipv4Net },
+    #[error("Reserved address {0} is outside the server subnet")]
+    ReservedOutside(Ipv4Addr),
+}
+
+/// Static address for one client identity
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reservation {
+    pub identity: String,
+    pub ip: Ipv4Addr,
+}
+
+impl FromStr for Reservation {
+    type Err = PoolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PoolError::Reservation(s.to_string());
+        let (identity, ip) = s.split_once('=').ok_or_else(err)?;
+        if identity.is_empty() {
+            return Err(err());
+        }
+        Ok(Self {
+            identity: identity.to_string(),
+            ip: ip.parse().map_err(|_| err())?,
+        })
+    }
+}
+
+impl fmt::Display for Reservation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.identity, self.ip)
+    }
+}
+
+struct Lease {
+    addr: Ipv4Addr,
+    /// Open connections of the identity holding the lease
+    conns: usize,
+    /// When the last connection closed, None while in use
+    idle_since: Option<Instant>,
+}
+
+/// Address pool shared by all client connections
+pub struct AddressPool {
+    range: Ipv4Net,
+    /// Server subnet and address; leases use its prefix length
+    subnet: Ipv4Net,
+    subnet6: Option<Ipv6Net>,
+    reservations: HashMap<String, Ipv4Addr>,
+    leases: Mutex<HashMap<String, Lease>>,
+}
+
+impl AddressPool {
+    /// Create a pool leasing from `range`, which must lie in the server's
+    /// own `subnet`
+    pub fn new(
+        range: Ipv4Net,
+        subnet: Ipv4Net,
+        subnet6: Option<Ipv6Net>,
+        reservations: &[Reservation],
+    ) -> Result<Self, PoolError> {
+        if !subnet.trunc().contains(&range) {
+            return Err(PoolError::OutsideSubnet { pool: range, subnet });
+        }
+        if let Some(r) = reservations.iter().find(|r| !subnet.contains(&r.ip)) {
+            return Err(PoolError::ReservedOutside(r.ip));
+        }
+
+        Ok(Self {
+            range: range.trunc(),
+            subnet,
+            subnet6,
+            reservations: reservations.iter().map(|r| (r.identity.clone(), r.ip)).collect(),
+            leases: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Lease addresses to a new connection of `identity`, reusing the
+    /// identity's previous lease. None when the pool is exhausted
+    pub fn acquire(&self, identity: &str) -> Option<MessageLease> {
+        let mut leases = self.leases.lock();
+        if let Some(lease) = leases.get_mut(identity) {
+            lease.conns += 1;
+            lease.idle_since = None;
+            return Some(self.to_message(lease.addr));
+        }
+
+        let addr = match self.reservations.get(identity) {
+            Some(addr) => *addr,
+            None => self.free_addr(&leases).or_else(|| Self::evict_idle(&mut leases))?,
+        };
+        leases.insert(identity.to_string(), Lease { addr, conns: 1, idle_since: None });
+        Some(self.to_message(addr))
+    }
+
+    /// A connection of `identity` closed
+    pub fn release(&self, identity: &str) {
+        if let Some(lease) = self.leases.lock().get_mut(identity) {
+            lease.conns = lease.conns.saturating_sub(1);
+            if lease.conns == 0 {
+                lease.idle_since = Some(Instant::now());
+            }
+        }
+    }
+
+    /// First pool address not leased, reserved or used by the server
+    fn free_addr(&self, leases: &HashMap<String, Lease>) -> Option<Ipv4Addr> {
+        let used: HashSet<Ipv4Addr> = leases
+            .values()
+            .map(|lease| lease.addr)
+            .chain(self.reservations.values().copied())
+            .collect();
+        let server6 = self.subnet6.map(|net| net.addr());
+
+        self.range.hosts().find(|addr| {
+            *addr != self.subnet.addr()
+                && *addr != self.subnet.network()
+                && *addr != self.subnet.broadcast()
+                && !used.contains(addr)
+                && (server6.is_none() || self.addr6(*addr) != server6)
+        })
+    }
+
+    /// Take the address of the dynamic lease idle the longest
+    fn evict_idle(leases: &mut HashMap<String, Lease>) -> Option<Ipv4Addr> {
+        let identity = leases
+            .iter()
+            .filter_map(|(identity, lease)| lease.idle_since.map(|since| (since, identity)))
+            .min()
+            .map(|(_, identity)| identity.clone())?;
+        leases.remove(&identity).map(|lease| lease.addr)
+    }
+
+    /// IPv6 address at the same host offset as `addr`
+    fn addr6(&self, addr: Ipv4Addr) -> Option<Ipv6Addr> {
+        let offset = u32::from(addr) - u32::from(self.subnet.network());
+        self.subnet6
+            .map(|net| Ipv6Addr::from(u128::from(net.network()) + u128::from(offset)))
+    }
+
+    fn to_message(&self, addr: Ipv4Addr) -> MessageLease {
+        MessageLease {
+            ip: format!("{}/{}", addr, self.subnet.prefix_len()),
+            ip6: match (self.addr6(addr), self.subnet6) {
+                (Some(addr6), Some(net)) => format!("{}/{}", addr6, net.prefix_len()),
+                _ => String::new(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_leases() {
+        let pool = AddressPool::new(
+            "10.0.0.0/29".parse().unwrap(),
+            "10.0.0.1/24".parse().unwrap(),
+            Some("fd00::1/64".parse().unwrap()),
+            &["laptop=10.0.0.100".parse().unwrap()],
+        )
+        .unwrap();
+
+        let a = pool.acquire("a").unwrap();
+        assert_eq!(a.ip, "10.0.0.2/24");
+        assert_eq!(a.ip6, "fd00::2/64");
+        assert_eq!(pool.acquire("a").unwrap(), a);
+        assert_eq!(pool.acquire("laptop").unwrap().ip, "10.0.0.100/24");
+
+        // 10.0.0.3 to 10.0.0.6 remain
+        for identity in ["b", "c", "d", "e"] {
+            assert!(pool.acquire(identity).is_some());
+        }
+        assert!(pool.acquire("f").is_none());
+
+        // An idle lease is kept for its identity until the pool runs out
+        pool.release("b");
+        assert_eq!(pool.acquire("b").unwrap().ip, "10.0.0.3/24");
+        pool.release("b");
+        assert_eq!(pool.acquire("f").unwrap().ip, "10.0.0.3/24");
+        assert!("no-address".parse::<Reservation>().is_err());
+    }
+}
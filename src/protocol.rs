@@ -7,19 +7,20 @@ use bytes::{Buf, BufMut};
 use prost::encoding::{DecodeContext, WireType};
 use prost::{DecodeError, Message};
 
-/// Envelope message containing either Ping or Packet
+/// Envelope message containing a Ping, Packet or proxy stream control message
 #[derive(Clone, PartialEq, Debug, Default)]
 pub struct Envelope {
     pub r#type: Option<envelope::Type>,
 }
 
 pub mod envelope {
-    use super::*;
-
     #[derive(Clone, PartialEq, Debug)]
     pub enum Type {
         Ping(super::MessagePing),
         Packet(super::MessagePacket),
+        StreamOpen(super::MessageStreamOpen),
+        StreamReply(super::MessageStreamReply),
+        Batch(super::MessageBatch),
     }
 }
 
@@ -36,6 +37,15 @@ impl Message for Envelope {
                 envelope::Type::Packet(packet) => {
                     prost::encoding::message::encode(2, packet, buf);
                 }
+                envelope::Type::StreamOpen(open) => {
+                    prost::encoding::message::encode(3, open, buf);
+                }
+                envelope::Type::StreamReply(reply) => {
+                    prost::encoding::message::encode(4, reply, buf);
+                }
+                envelope::Type::Batch(batch) => {
+                    prost::encoding::message::encode(5, batch, buf);
+                }
             }
         }
     }
@@ -63,6 +73,24 @@ impl Message for Envelope {
                 self.r#type = Some(envelope::Type::Packet(packet));
                 Ok(())
             }
+            3 => {
+                let mut open = MessageStreamOpen::default();
+                prost::encoding::message::merge(wire_type, &mut open, buf, ctx)?;
+                self.r#type = Some(envelope::Type::StreamOpen(open));
+                Ok(())
+            }
+            4 => {
+                let mut reply = MessageStreamReply::default();
+                prost::encoding::message::merge(wire_type, &mut reply, buf, ctx)?;
+                self.r#type = Some(envelope::Type::StreamReply(reply));
+                Ok(())
+            }
+            5 => {
+                let mut batch = MessageBatch::default();
+                prost::encoding::message::merge(wire_type, &mut batch, buf, ctx)?;
+                self.r#type = Some(envelope::Type::Batch(batch));
+                Ok(())
+            }
             _ => prost::encoding::skip_field(wire_type, tag, buf, ctx),
         }
     }
@@ -71,6 +99,9 @@ impl Message for Envelope {
         match &self.r#type {
             Some(envelope::Type::Ping(ping)) => prost::encoding::message::encoded_len(1, ping),
             Some(envelope::Type::Packet(packet)) => prost::encoding::message::encoded_len(2, packet),
+            Some(envelope::Type::StreamOpen(open)) => prost::encoding::message::encoded_len(3, open),
+            Some(envelope::Type::StreamReply(reply)) => prost::encoding::message::encoded_len(4, reply),
+            Some(envelope::Type::Batch(batch)) => prost::encoding::message::encoded_len(5, batch),
             None => 0,
         }
     }
@@ -88,6 +119,10 @@ pub struct MessagePing {
     pub local_private_addr: String,
     pub ip: String,
     pub dc: String,
+    /// IPv6 virtual address, empty when the client has none
+    pub ip6: String,
+    /// Subnets (CIDR) reachable through the client
+    pub routes: Vec<String>,
 }
 
 impl Message for MessagePing {
@@ -110,6 +145,10 @@ impl Message for MessagePing {
         if !self.dc.is_empty() {
             prost::encoding::string::encode(5, &self.dc, buf);
         }
+        if !self.ip6.is_empty() {
+            prost::encoding::string::encode(6, &self.ip6, buf);
+        }
+        prost::encoding::string::encode_repeated(7, &self.routes, buf);
     }
 
     fn merge_field(
@@ -128,6 +167,8 @@ impl Message for MessagePing {
             3 => prost::encoding::string::merge(wire_type, &mut self.local_private_addr, buf, ctx),
             4 => prost::encoding::string::merge(wire_type, &mut self.ip, buf, ctx),
             5 => prost::encoding::string::merge(wire_type, &mut self.dc, buf, ctx),
+            6 => prost::encoding::string::merge(wire_type, &mut self.ip6, buf, ctx),
+            7 => prost::encoding::string::merge_repeated(wire_type, &mut self.routes, buf, ctx),
             _ => prost::encoding::skip_field(wire_type, tag, buf, ctx),
         }
     }
@@ -149,6 +190,10 @@ impl Message for MessagePing {
         if !self.dc.is_empty() {
             len += prost::encoding::string::encoded_len(5, &self.dc);
         }
+        if !self.ip6.is_empty() {
+            len += prost::encoding::string::encoded_len(6, &self.ip6);
+        }
+        len += prost::encoding::string::encoded_len_repeated(7, &self.routes);
         len
     }
 
@@ -158,6 +203,8 @@ impl Message for MessagePing {
         self.local_private_addr.clear();
         self.ip.clear();
         self.dc.clear();
+        self.ip6.clear();
+        self.routes.clear();
     }
 }
 
@@ -205,3 +252,253 @@ impl Message for MessagePacket {
         self.payload.clear();
     }
 }
+
+/// Batch message: several IP packets sealed into one frame
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct MessageBatch {
+    pub packets: Vec<MessagePacket>,
+}
+
+impl Message for MessageBatch {
+    fn encode_raw(&self, buf: &mut impl BufMut)
+    where
+        Self: Sized,
+    {
+        prost::encoding::message::encode_repeated(1, &self.packets, buf);
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut impl Buf,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError>
+    where
+        Self: Sized,
+    {
+        match tag {
+            1 => prost::encoding::message::merge_repeated(wire_type, &mut self.packets, buf, ctx),
+            _ => prost::encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        prost::encoding::message::encoded_len_repeated(1, &self.packets)
+    }
+
+    fn clear(&mut self) {
+        self.packets.clear();
+    }
+}
+
+/// Stream open message: first frame on a proxied QUIC stream
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct MessageStreamOpen {
+    pub address: String,
+}
+
+impl Message for MessageStreamOpen {
+    fn encode_raw(&self, buf: &mut impl BufMut)
+    where
+        Self: Sized,
+    {
+        if !self.address.is_empty() {
+            prost::encoding::string::encode(1, &self.address, buf);
+        }
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut impl Buf,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError>
+    where
+        Self: Sized,
+    {
+        match tag {
+            1 => prost::encoding::string::merge(wire_type, &mut self.address, buf, ctx),
+            _ => prost::encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        if !self.address.is_empty() {
+            prost::encoding::string::encoded_len(1, &self.address)
+        } else {
+            0
+        }
+    }
+
+    fn clear(&mut self) {
+        self.address.clear();
+    }
+}
+
+/// Stream reply message: result of dialing the address in MessageStreamOpen
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct MessageStreamReply {
+    /// SOCKS5 reply code, 0 on success
+    pub reply: u32,
+    pub bind_addr: String,
+}
+
+impl Message for MessageStreamReply {
+    fn encode_raw(&self, buf: &mut impl BufMut)
+    where
+        Self: Sized,
+    {
+        if self.reply != 0 {
+            prost::encoding::uint32::encode(1, &self.reply, buf);
+        }
+        if !self.bind_addr.is_empty() {
+            prost::encoding::string::encode(2, &self.bind_addr, buf);
+        }
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut impl Buf,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError>
+    where
+        Self: Sized,
+    {
+        match tag {
+            1 => prost::encoding::uint32::merge(wire_type, &mut self.reply, buf, ctx),
+            2 => prost::encoding::string::merge(wire_type, &mut self.bind_addr, buf, ctx),
+            _ => prost::encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        let mut len = 0;
+        if self.reply != 0 {
+            len += prost::encoding::uint32::encoded_len(1, &self.reply);
+        }
+        if !self.bind_addr.is_empty() {
+            len += prost::encoding::string::encoded_len(2, &self.bind_addr);
+        }
+        len
+    }
+
+    fn clear(&mut self) {
+        self.reply = 0;
+        self.bind_addr.clear();
+    }
+}
+
+/// Lease request sent by the client during the session handshake
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct MessageLeaseRequest {
+    /// Stable client identity, keys static reservations
+    pub identity: String,
+    /// Proxy-only session: no packet tunnel, so no address is leased
+    pub proxy_only: bool,
+}
+
+impl Message for MessageLeaseRequest {
+    fn encode_raw(&self, buf: &mut impl BufMut)
+    where
+        Self: Sized,
+    {
+        if !self.identity.is_empty() {
+            prost::encoding::string::encode(1, &self.identity, buf);
+        }
+        if self.proxy_only {
+            prost::encoding::bool::encode(2, &self.proxy_only, buf);
+        }
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut impl Buf,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError>
+    where
+        Self: Sized,
+    {
+        match tag {
+            1 => prost::encoding::string::merge(wire_type, &mut self.identity, buf, ctx),
+            2 => prost::encoding::bool::merge(wire_type, &mut self.proxy_only, buf, ctx),
+            _ => prost::encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        let mut len = 0;
+        if !self.identity.is_empty() {
+            len += prost::encoding::string::encoded_len(1, &self.identity);
+        }
+        if self.proxy_only {
+            len += prost::encoding::bool::encoded_len(2, &self.proxy_only);
+        }
+        len
+    }
+
+    fn clear(&mut self) {
+        self.identity.clear();
+        self.proxy_only = false;
+    }
+}
+
+/// Virtual addresses leased to a client, in CIDR form; empty when the
+/// server has no address pool
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct MessageLease {
+    pub ip: String,
+    pub ip6: String,
+}
+
+impl Message for MessageLease {
+    fn encode_raw(&self, buf: &mut impl BufMut)
+    where
+        Self: Sized,
+    {
+        if !self.ip.is_empty() {
+            prost::encoding::string::encode(1, &self.ip, buf);
+        }
+        if !self.ip6.is_empty() {
+            prost::encoding::string::encode(2, &self.ip6, buf);
+        }
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut impl Buf,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError>
+    where
+        Self: Sized,
+    {
+        match tag {
+            1 => prost::encoding::string::merge(wire_type, &mut self.ip, buf, ctx),
+            2 => prost::encoding::string::merge(wire_type, &mut self.ip6, buf, ctx),
+            _ => prost::encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        let mut len = 0;
+        if !self.ip.is_empty() {
+            len += prost::encoding::string::encoded_len(1, &self.ip);
+        }
+        if !self.ip6.is_empty() {
+            len += prost::encoding::string::encoded_len(2, &self.ip6);
+        }
+        len
+    }
+
+    fn clear(&mut self) {
+        self.ip.clear();
+        self.ip6.clear();
+    }
+}
@@ -0,0 +1,179 @@
+//! Prefix-based routing table for the server
+//!
+//! Routes map a subnet to the client connections (next hops) that serve
+//! it. Lookups pick the longest matching prefix, then a next hop by flow
+//! hash so the packets of one flow stay on one connection.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::net::IpAddr;
+use ipnet::IpNet;
+use parking_lot::RwLock;
+
+/// Next hop: the address key of a client connection
+pub type NextHop = String;
+
+#[derive(Default)]
+struct Inner {
+    routes: HashMap<IpNet, BTreeSet<NextHop>>,
+    /// Prefix lengths in use per family, so lookups only probe those
+    v4_prefixes: BTreeSet<u8>,
+    v6_prefixes: BTreeSet<u8>,
+    /// Bumped whenever a next hop is added or removed
+    version: u64,
+}
+
+impl Inner {
+    fn prefixes(&mut self, net: &IpNet) -> &mut BTreeSet<u8> {
+        match net {
+            IpNet::V4(_) => &mut self.v4_prefixes,
+            IpNet::V6(_) => &mut self.v6_prefixes,
+        }
+    }
+}
+
+/// Longest-prefix-match routing table
+#[derive(Default)]
+pub struct RouteTable {
+    inner: RwLock<Inner>,
+}
+
+impl RouteTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a next hop for a subnet; host bits are ignored. Returns true
+    /// when the subnet had no route before
+    pub fn insert(&self, net: IpNet, hop: NextHop) -> bool {
+        let net = net.trunc();
+        let mut inner = self.inner.write();
+        inner.prefixes(&net).insert(net.prefix_len());
+        let hops = inner.routes.entry(net).or_default();
+        let is_new = hops.is_empty();
+        if hops.insert(hop) {
+            inner.version += 1;
+        }
+        is_new
+    }
+
+    /// Remove a next hop from a subnet, dropping the route when it was the
+    /// last. Returns true when the route was dropped
+    pub fn remove(&self, net: &IpNet, hop: &str) -> bool {
+        let mut inner = self.inner.write();
+        let Some(hops) = inner.routes.get_mut(net) else {
+            return false;
+        };
+        if !hops.remove(hop) {
+            return false;
+        }
+        let emptied = hops.is_empty();
+        inner.version += 1;
+        if !emptied {
+            return false;
+        }
+
+        inner.routes.remove(net);
+        let prefix_len = net.prefix_len();
+        let in_use = inner
+            .routes
+            .keys()
+            .any(|other| other.prefix_len() == prefix_len && other.addr().is_ipv4() == net.addr().is_ipv4());
+        if !in_use {
+            inner.prefixes(net).remove(&prefix_len);
+        }
+        true
+    }
+
+    /// Remove a next hop from every route
+    pub fn remove_hop(&self, hop: &str) {
+        for (net, _) in self.snapshot().into_iter().filter(|(_, hops)| hops.iter().any(|h| h == hop)) {
+            self.remove(&net, hop);
+        }
+    }
+
+    /// Find the longest prefix containing `addr` and pick one of its next
+    /// hops by `flow`, a hash of the packet's 5-tuple
+    pub fn lookup(&self, addr: IpAddr, flow: u64) -> Option<(IpNet, NextHop)> {
+        let inner = self.inner.read();
+        let prefixes = match addr {
+            IpAddr::V4(_) => &inner.v4_prefixes,
+            IpAddr::V6(_) => &inner.v6_prefixes,
+        };
+
+        prefixes.iter().rev().find_map(|&prefix_len| {
+            let net = IpNet::new(addr, prefix_len).ok()?.trunc();
+            let hops = inner.routes.get(&net)?;
+            let hop = hops.iter().nth((flow % hops.len().max(1) as u64) as usize)?;
+            Some((net, hop.clone()))
+        })
+    }
+
+    /// Counter that changes whenever the table does
+    pub fn version(&self) -> u64 {
+        self.inner.read().version
+    }
+
+    /// Copy of all routes, for periodic cleanup and logging
+    pub fn snapshot(&self) -> Vec<(IpNet, Vec<NextHop>)> {
+        self.inner
+            .read()
+            .routes
+            .iter()
+            .map(|(net, hops)| (*net, hops.iter().cloned().collect()))
+            .collect()
+    }
+}
+
+impl fmt::Debug for RouteTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.snapshot()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_longest_prefix_match() {
+        let table = RouteTable::new();
+        table.insert("10.0.0.0/8".parse().unwrap(), "a".to_string());
+        table.insert("10.1.0.0/16".parse().unwrap(), "b".to_string());
+        table.insert("10.1.2.3/32".parse().unwrap(), "c".to_string());
+        table.insert("fd00::/64".parse().unwrap(), "d".to_string());
+
+        let hop = |addr: &str| table.lookup(addr.parse().unwrap(), 0).map(|(_, hop)| hop);
+        assert_eq!(hop("10.1.2.3").as_deref(), Some("c"));
+        assert_eq!(hop("10.1.2.4").as_deref(), Some("b"));
+        assert_eq!(hop("10.2.0.1").as_deref(), Some("a"));
+        assert_eq!(hop("fd00::5").as_deref(), Some("d"));
+        assert_eq!(hop("192.168.0.1"), None);
+
+        // Only real changes move the version
+        let version = table.version();
+        table.insert("10.1.2.3/32".parse().unwrap(), "c".to_string());
+        table.remove(&"10.1.0.0/16".parse().unwrap(), "z");
+        assert_eq!(table.version(), version);
+
+        table.remove(&"10.1.0.0/16".parse().unwrap(), "b");
+        assert_ne!(table.version(), version);
+        assert_eq!(hop("10.1.2.4").as_deref(), Some("a"));
+        table.remove_hop("a");
+        assert_eq!(hop("10.1.2.4"), None);
+    }
+
+    #[test]
+    fn test_flow_sticks_to_hop() {
+        let table = RouteTable::new();
+        let net: IpNet = "10.0.0.2/32".parse().unwrap();
+        for hop in ["10.0.0.2:1000", "10.0.0.2:2000", "10.0.0.2:3000"] {
+            table.insert(net, hop.to_string());
+        }
+
+        let hop = |flow| table.lookup(net.addr(), flow).map(|(_, hop)| hop).unwrap();
+        assert_eq!(hop(7), hop(7));
+        let used: BTreeSet<_> = (0..3).map(hop).collect();
+        assert_eq!(used.len(), 3);
+    }
+}
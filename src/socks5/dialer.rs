@@ -0,0 +1,88 @@
+//! Outbound dialers for CONNECT requests
+
+use std::io;
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use super::request::{
+    AddrSpec, CONNECTION_REFUSED, HOST_UNREACHABLE, NETWORK_UNREACHABLE,
+};
+
+pub type BoxedReader = Box<dyn AsyncRead + Unpin + Send>;
+pub type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct DialError {
+    /// SOCKS5 reply code to send back to the client
+    pub reply: u8,
+    pub message: String,
+}
+
+impl DialError {
+    pub fn new(reply: u8, message: impl Into<String>) -> Self {
+        Self {
+            reply,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<io::Error> for DialError {
+    fn from(e: io::Error) -> Self {
+        let msg = e.to_string();
+        let reply = if msg.contains("refused") {
+            CONNECTION_REFUSED
+        } else if msg.contains("unreachable") {
+            NETWORK_UNREACHABLE
+        } else {
+            HOST_UNREACHABLE
+        };
+        Self::new(reply, msg)
+    }
+}
+
+/// Established outbound connection
+pub struct Connected {
+    pub reader: BoxedReader,
+    pub writer: BoxedWriter,
+    /// Bound address reported to the client in the reply
+    pub bind: AddrSpec,
+}
+
+/// Dialer trait for opening the outbound side of a CONNECT
+#[async_trait]
+pub trait Dialer: Send + Sync {
+    async fn dial(&self, dest: &AddrSpec) -> Result<Connected, DialError>;
+
+    /// Whether FQDNs should be passed through unresolved to the dialer
+    fn resolves_remotely(&self) -> bool {
+        false
+    }
+}
+
+/// Dialer connecting directly from this host
+pub struct DirectDialer;
+
+#[async_trait]
+impl Dialer for DirectDialer {
+    async fn dial(&self, dest: &AddrSpec) -> Result<Connected, DialError> {
+        let target = TcpStream::connect(dest.address()).await?;
+
+        let local_addr = target.local_addr()?;
+        let bind = AddrSpec {
+            fqdn: None,
+            ip: Some(local_addr.ip()),
+            port: local_addr.port(),
+        };
+
+        let (reader, writer) = target.into_split();
+        Ok(Connected {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            bind,
+        })
+    }
+}
@@ -1,11 +1,13 @@
 //! SOCKS5 proxy module
 
 pub mod auth;
+pub mod dialer;
 pub mod request;
 pub mod resolver;
 pub mod server;
 
 pub use auth::*;
+pub use dialer::*;
 pub use request::*;
 pub use resolver::*;
 pub use server::*;
@@ -13,9 +15,8 @@ pub use server::*;
 use tracing::info;
 
 /// Start the SOCKS5 server on the given port
-pub async fn start_socks5(port: &str) {
+pub async fn start_socks5(port: &str, config: Config) {
     let addr = format!("0.0.0.0:{}", port);
-    let config = Config::default();
     
     loop {
         let server = match Server::new(config.clone()) {
@@ -5,18 +5,18 @@ use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{debug, error};
+use tracing::{debug, error, info};
 
 use super::auth::{
-    read_methods, no_acceptable_auth, Authenticator, AuthContext, AuthError,
+    read_methods, no_acceptable_auth, Authenticator, AuthContext,
     SOCKS5_VERSION,
 };
 use super::request::{
-    send_reply, AddrSpec, Request,
+    proxy, send_reply, AddrSpec, Request,
     CONNECT_COMMAND, BIND_COMMAND, ASSOCIATE_COMMAND,
-    SUCCESS_REPLY, SERVER_FAILURE, RULE_FAILURE, HOST_UNREACHABLE,
-    CONNECTION_REFUSED, NETWORK_UNREACHABLE, COMMAND_NOT_SUPPORTED,
+    SUCCESS_REPLY, RULE_FAILURE, HOST_UNREACHABLE, COMMAND_NOT_SUPPORTED,
 };
+use super::dialer::{Connected, Dialer, DirectDialer};
 use super::resolver::{DnsResolver, NameResolver};
 
 /// Rule set trait for allowing/denying requests
@@ -38,6 +38,7 @@ impl RuleSet for PermitAll {
 pub struct Config {
     pub resolver: Arc<dyn NameResolver>,
     pub rules: Arc<dyn RuleSet>,
+    pub dialer: Arc<dyn Dialer>,
     pub bind_ip: Option<IpAddr>,
 }
 
@@ -46,6 +47,7 @@ impl Default for Config {
         Self {
             resolver: Arc::new(DnsResolver),
             rules: Arc::new(PermitAll),
+            dialer: Arc::new(DirectDialer),
             bind_ip: None,
         }
     }
@@ -159,8 +161,8 @@ where
     R: AsyncReadExt + Unpin + Send,
     W: AsyncWriteExt + Unpin + Send,
 {
-    // Resolve FQDN if needed
-    if let Some(fqdn) = &request.dest_addr.fqdn {
+    // Resolve FQDN if needed, unless the dialer resolves on the far side
+    if let Some(fqdn) = request.dest_addr.fqdn.as_ref().filter(|_| !config.dialer.resolves_remotely()) {
         match config.resolver.resolve(fqdn).await {
             Ok(ip) => {
                 request.dest_addr.ip = Some(ip);
@@ -180,7 +182,7 @@ where
 
     // Handle command
     match request.command {
-        CONNECT_COMMAND => handle_connect(reader, writer, &request).await,
+        CONNECT_COMMAND => handle_connect(reader, writer, &request, config).await,
         BIND_COMMAND => {
             send_reply(writer, COMMAND_NOT_SUPPORTED, None).await?;
             Err("Bind command not supported".into())
@@ -197,9 +199,10 @@ where
 }
 
 async fn handle_connect<R, W>(
-    _client_reader: &mut R,
+    client_reader: &mut R,
     client_writer: &mut W,
     request: &Request,
+    config: &Config,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
 where
     R: AsyncReadExt + Unpin + Send,
@@ -207,39 +210,38 @@ where
 {
     // Connect to target
     let target_addr = request.dest_addr.address();
-    let target = match TcpStream::connect(&target_addr).await {
+    let target = match config.dialer.dial(&request.dest_addr).await {
         Ok(t) => t,
         Err(e) => {
-            let msg = e.to_string();
-            let resp = if msg.contains("refused") {
-                CONNECTION_REFUSED
-            } else if msg.contains("unreachable") {
-                NETWORK_UNREACHABLE
-            } else {
-                HOST_UNREACHABLE
-            };
-            send_reply(client_writer, resp, None).await?;
+            send_reply(client_writer, e.reply, None).await?;
             return Err(format!("Connect to {} failed: {}", target_addr, e).into());
         }
     };
 
-    // Get local address for reply
-    let local_addr = target.local_addr()?;
-    let bind = AddrSpec {
-        fqdn: None,
-        ip: Some(local_addr.ip()),
-        port: local_addr.port(),
-    };
-
     // Send success reply
-    send_reply(client_writer, SUCCESS_REPLY, Some(&bind)).await?;
+    send_reply(client_writer, SUCCESS_REPLY, Some(&target.bind)).await?;
 
-    // Note: Full bidirectional proxy would require owning the streams
-    // This is a simplified placeholder that establishes connection
     debug!(target = %target_addr, "SOCKS5 connect established");
 
-    // Keep the target connection alive until it closes
-    drop(target);
+    // Relay both directions; each side is half-closed once its source hits EOF
+    let Connected { reader: mut target_reader, writer: mut target_writer, .. } = target;
+    let (sent, received) = tokio::try_join!(
+        proxy(&mut *client_reader, &mut target_writer),
+        proxy(&mut target_reader, &mut *client_writer),
+    )?;
+
+    let client_addr = request
+        .remote_addr
+        .as_ref()
+        .map(|a| a.to_string())
+        .unwrap_or_default();
+    info!(
+        client = %client_addr,
+        target = %target_addr,
+        sent = sent,
+        received = received,
+        "SOCKS5 session closed"
+    );
 
     Ok(())
 }
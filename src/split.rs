@@ -0,0 +1,150 @@
+//! Split tunneling by a CIDR list file
+//!
+//! Only destinations in the listed subnets go through the tunnel. The file
+//! holds one subnet per line, `#` starts a comment, and it is re-read when
+//! its modification time changes.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use thiserror::Error;
+
+use crate::route::RouteTable;
+
+/// Next hop used for listed subnets in the lookup table
+const TUNNEL: &str = "tunnel";
+
+#[derive(Error, Debug)]
+pub enum SplitError {
+    #[error("Failed to read split route list {path}: {source}")]
+    Read { path: String, source: std::io::Error },
+    #[error("Invalid subnet {value} on line {line}")]
+    Subnet { line: usize, value: String },
+}
+
+/// Subnets added to and removed from the list by a reload
+#[derive(Debug, Default, PartialEq)]
+pub struct SplitChanges {
+    pub added: Vec<IpNet>,
+    pub removed: Vec<IpNet>,
+}
+
+struct State {
+    nets: HashSet<IpNet>,
+    modified: Option<SystemTime>,
+}
+
+/// Subnets routed through the tunnel
+pub struct SplitRoutes {
+    path: PathBuf,
+    table: RouteTable,
+    state: Mutex<State>,
+}
+
+impl SplitRoutes {
+    /// Create an empty list backed by `path`. Destinations in `always`,
+    /// such as the tunnel's own subnets, are never dropped
+    pub fn new(path: impl Into<PathBuf>, always: &[IpNet]) -> Self {
+        let table = RouteTable::new();
+        for net in always {
+            table.insert(*net, String::new());
+        }
+        Self {
+            path: path.into(),
+            table,
+            state: Mutex::new(State { nets: HashSet::new(), modified: None }),
+        }
+    }
+
+    /// Check whether a destination goes through the tunnel
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.table.lookup(addr, 0).is_some()
+    }
+
+    /// Re-read the list if the file changed since the last load. On error
+    /// the current list is kept
+    pub fn reload(&self) -> Result<SplitChanges, SplitError> {
+        let read_err = |source| SplitError::Read { path: self.path.display().to_string(), source };
+        let modified = std::fs::metadata(&self.path).and_then(|meta| meta.modified()).map_err(read_err)?;
+
+        let mut state = self.state.lock();
+        if state.modified == Some(modified) {
+            return Ok(SplitChanges::default());
+        }
+        let nets = parse_list(&std::fs::read_to_string(&self.path).map_err(read_err)?)?;
+
+        let changes = SplitChanges {
+            added: nets.difference(&state.nets).copied().collect(),
+            removed: state.nets.difference(&nets).copied().collect(),
+        };
+        for net in &changes.added {
+            self.table.insert(*net, TUNNEL.to_string());
+        }
+        for net in &changes.removed {
+            self.table.remove(net, TUNNEL);
+        }
+        state.nets = nets;
+        state.modified = Some(modified);
+        Ok(changes)
+    }
+}
+
+/// Parse a CIDR list, one subnet per line with `#` comments
+fn parse_list(text: &str) -> Result<HashSet<IpNet>, SplitError> {
+    let mut nets = HashSet::new();
+    for (i, line) in text.lines().enumerate() {
+        let value = line.split('#').next().unwrap_or_default().trim();
+        if value.is_empty() {
+            continue;
+        }
+        let net = value
+            .parse::<IpNet>()
+            .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+            .map_err(|_| SplitError::Subnet { line: i + 1, value: value.to_string() })?;
+        nets.insert(net.trunc());
+    }
+    Ok(nets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Rewrite the list with a modification time `secs` after the epoch,
+    /// so a reload sees the change however fast the test runs
+    fn rewrite(path: &std::path::Path, text: &str, secs: u64) {
+        std::fs::write(path, text).unwrap();
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+    }
+
+    #[test]
+    fn test_split_reload() {
+        let path = std::env::temp_dir().join(format!("qtun-split-{}.txt", std::process::id()));
+        std::fs::write(&path, "# corp\n10.1.0.0/16\n172.16.5.9 # host\n\n").unwrap();
+
+        let split = SplitRoutes::new(&path, &["10.239.0.0/24".parse().unwrap()]);
+        let changes = split.reload().unwrap();
+        assert_eq!(changes.added.len(), 2);
+        assert!(split.contains("10.1.2.3".parse().unwrap()));
+        assert!(split.contains("172.16.5.9".parse().unwrap()));
+        assert!(split.contains("10.239.0.1".parse().unwrap()));
+        assert!(!split.contains("8.8.8.8".parse().unwrap()));
+        assert_eq!(split.reload().unwrap(), SplitChanges::default());
+
+        rewrite(&path, "10.2.0.0/16\n", 1_000);
+        let changes = split.reload().unwrap();
+        assert_eq!(changes.added, vec!["10.2.0.0/16".parse::<IpNet>().unwrap()]);
+        assert_eq!(changes.removed.len(), 2);
+        assert!(!split.contains("10.1.2.3".parse().unwrap()));
+
+        rewrite(&path, "not-a-subnet\n", 2_000);
+        assert!(split.reload().is_err());
+        assert!(split.contains("10.2.0.1".parse().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
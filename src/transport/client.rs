@@ -1,29 +1,44 @@
 //! QUIC Client implementation
 
-use std::net::ToSocketAddrs;
+use std::net::{IpAddr, ToSocketAddrs};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicI64, Ordering};
-use std::time::Duration;
-use bytes::BytesMut;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use prost::Message;
-use quinn::{ClientConfig, Connection, Endpoint, TransportConfig};
-use tokio::sync::mpsc;
+use quinn::{ClientConfig, Connection, ConnectionError, Endpoint, TransportConfig};
+use tokio::sync::watch;
 use tokio::time::{interval, sleep};
 use tracing::{debug, error, info, warn};
 
 use super::client_conn::{ClientConn, run_client_conn};
+use super::crypto::{PreSharedKey, SessionCiphers};
+use super::handshake::{client_handshake, HandshakeError};
+use super::server_conn::CLOSE_IP_CONFLICT;
+use super::tls::client_crypto_config;
 use super::TransportHandler;
 use crate::config::get_config;
 use crate::iface::PacketIP;
-use crate::protocol::{Envelope, MessagePacket, MessagePing, envelope};
+use crate::protocol::{Envelope, MessageLease, MessageLeaseRequest, MessagePing, envelope};
+use crate::utils::Backoff;
+
+/// Connection slot; the supervisor swaps in a new connection after a drop
+type ConnSlot = parking_lot::RwLock<Arc<ClientConn>>;
+
+/// Connections lasting at least this long reset the reconnect backoff
+const STABLE_CONN_DURATION: Duration = Duration::from_secs(10);
 
 pub struct Client<H: TransportHandler + 'static> {
     remote_addr: String,
     key: String,
+    psk: Option<Arc<PreSharedKey>>,
     threads: usize,
     handler: Arc<H>,
-    conns: Vec<Arc<ClientConn>>,
-    serial: AtomicI64,
+    conns: Arc<Vec<ConnSlot>>,
+    stopped: Arc<AtomicBool>,
+    /// First address lease received from the server
+    lease: Arc<watch::Sender<Option<MessageLease>>>,
+    /// Reason the client gave up, set when reconnecting cannot help
+    fatal: Arc<watch::Sender<Option<String>>>,
 }
 
 impl<H: TransportHandler + 'static> Client<H> {
@@ -31,36 +46,70 @@ impl<H: TransportHandler + 'static> Client<H> {
         Self {
             remote_addr,
             key,
+            psk: None,
             threads,
             handler,
-            conns: Vec::new(),
-            serial: AtomicI64::new(0),
+            conns: Arc::new(Vec::new()),
+            stopped: Arc::new(AtomicBool::new(false)),
+            lease: Arc::new(watch::channel(None).0),
+            fatal: Arc::new(watch::channel(None).0),
         }
     }
 
+    /// Wait until the client gives up on the server and return why
+    pub async fn wait_fatal(&self) -> anyhow::Error {
+        let mut fatal = self.fatal.subscribe();
+        let reason = fatal.wait_for(Option::is_some).await.map(|reason| reason.clone().unwrap_or_default());
+        anyhow::anyhow!(reason.unwrap_or_else(|_| "Client stopped".to_string()))
+    }
+
+    /// Wait for the first connection and return the addresses the server
+    /// leased, empty when it has no address pool
+    pub async fn wait_lease(&self) -> MessageLease {
+        let mut lease = self.lease.subscribe();
+        // The sender lives as long as the client, so this only fails on drop
+        let lease = lease.wait_for(Option::is_some).await.map(|lease| lease.clone().unwrap_or_default());
+        lease.unwrap_or_default()
+    }
+
     /// Start the client and connect to server
     pub async fn start(&mut self) -> anyhow::Result<()> {
-        self.conns = Vec::with_capacity(self.threads);
+        if !self.key.is_empty() {
+            self.psk = Some(Arc::new(PreSharedKey::new(&self.key)?));
+        }
+
+        // Fill every slot with a disconnected placeholder until the
+        // supervisor has established the real connection
+        let slots: Vec<ConnSlot> = (0..self.threads)
+            .map(|index| {
+                let (conn, _, _) = ClientConn::new(self.remote_addr.clone(), index);
+                parking_lot::RwLock::new(Arc::new(conn))
+            })
+            .collect();
+        self.conns = Arc::new(slots);
 
         for conn_index in 0..self.threads {
-            match self.create_connection(conn_index).await {
-                Ok(conn) => {
-                    self.conns.push(conn);
-                }
-                Err(e) => {
-                    warn!(
-                        index = conn_index,
-                        error = %e,
-                        "Failed to create connection"
-                    );
+            let remote_addr = self.remote_addr.clone();
+            let psk = self.psk.clone();
+            let handler = self.handler.clone();
+            let conns = self.conns.clone();
+            let stopped = self.stopped.clone();
+            let lease = self.lease.clone();
+            let fatal = self.fatal.clone();
+            tokio::spawn(async move {
+                let gave_up =
+                    supervise_connection(conn_index, remote_addr, psk, handler, conns, stopped.clone(), lease).await;
+                if let Some(reason) = gave_up {
+                    stopped.store(true, Ordering::Relaxed);
+                    fatal.send_replace(Some(reason));
                 }
-            }
+            });
         }
 
         info!(
             server_addr = %self.remote_addr,
-            conn_num = self.conns.len(),
-            "Connections have been established"
+            conn_num = self.threads,
+            "Connection supervisors started"
         );
 
         // Start ping loop
@@ -69,140 +118,56 @@ impl<H: TransportHandler + 'static> Client<H> {
         Ok(())
     }
 
-    async fn create_connection(&self, index: usize) -> anyhow::Result<Arc<ClientConn>> {
-        // Create QUIC endpoint
-        let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
-        
-        // Configure TLS
-        let crypto = rustls::ClientConfig::builder()
-            .dangerous()
-            .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
-            .with_no_client_auth();
-
-        let mut transport_config = TransportConfig::default();
-        transport_config.max_concurrent_bidi_streams(1000u32.into());
-        transport_config.receive_window(quinn::VarInt::from_u32(6 * 1024 * 1024));
-        transport_config.send_window(6 * 1024 * 1024);
-        transport_config.keep_alive_interval(Some(Duration::from_secs(30)));
-
-        let mut client_config = ClientConfig::new(Arc::new(
-            quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?
-        ));
-        client_config.transport_config(Arc::new(transport_config));
-        endpoint.set_default_client_config(client_config);
-
-        // Resolve server address
-        let server_addr = self.remote_addr
-            .to_socket_addrs()?
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("Cannot resolve server address"))?;
-
-        // Connect with retry
-        let mut connected = false;
-        let mut connection: Option<Connection> = None;
-        
-        for attempt in 0..10 {
-            match endpoint.connect(server_addr, "localhost") {
-                Ok(connecting) => {
-                    match connecting.await {
-                        Ok(conn) => {
-                            connection = Some(conn);
-                            connected = true;
-                            break;
-                        }
-                        Err(e) => {
-                            warn!(
-                                attempt = attempt,
-                                error = %e,
-                                "Connection attempt failed"
-                            );
-                        }
-                    }
-                }
-                Err(e) => {
-                    warn!(
-                        attempt = attempt,
-                        error = %e,
-                        "Failed to initiate connection"
-                    );
-                }
-            }
-            sleep(Duration::from_millis(500)).await;
-        }
-
-        if !connected {
-            anyhow::bail!("Failed to connect to server after 10 attempts");
-        }
-
-        let quinn_conn = connection.unwrap();
-        
-        // Create ClientConn
-        let (conn, write_rx, close_rx) = ClientConn::new(
-            self.remote_addr.clone(),
-            self.key.clone(),
-            index,
-        );
-        let conn = Arc::new(conn);
-
-        // Spawn connection handler
-        let handler = self.handler.clone();
-        let conn_clone = conn.clone();
-        tokio::spawn(async move {
-            if let Err(e) = run_client_conn(conn_clone, quinn_conn, handler, write_rx, close_rx).await {
-                error!(index = index, error = %e, "Client connection error");
-            }
-        });
-
-        Ok(conn)
-    }
-
     fn start_ping_loop(&self) {
-        let conns: Vec<Arc<ClientConn>> = self.conns.iter().cloned().collect();
-        let remote_addr = self.remote_addr.clone();
+        let conns = self.conns.clone();
         let key = self.key.clone();
+        let lease = self.lease.clone();
 
         tokio::spawn(async move {
             let mut ticker = interval(Duration::from_secs(1));
             loop {
                 ticker.tick().await;
-                
-                for conn in &conns {
+
+                let lease = lease.borrow().clone();
+                for slot in conns.iter() {
+                    let conn = slot.read().clone();
                     if conn.is_connected() {
-                        send_ping(conn, &key).await;
+                        send_ping(&conn, &key, lease.as_ref()).await;
                     }
                 }
             }
         });
     }
 
-    /// Send packet to server (load balanced across connections)
+    /// Send packet to server, spreading flows across connections while
+    /// keeping each flow on one so it is not reordered
     pub async fn send_packet(&self, pkt: &PacketIP) {
         if self.conns.is_empty() {
             return;
         }
 
-        let env = Envelope {
-            r#type: Some(envelope::Type::Packet(MessagePacket {
-                payload: pkt.as_bytes().to_vec(),
-            })),
+        let first = if self.threads == 1 {
+            0
+        } else {
+            (pkt.flow_hash() % self.conns.len() as u64) as usize
         };
 
-        let data = env.encode_to_vec();
-        
-        if self.threads == 1 {
-            self.conns[0].write(data).await;
-        } else {
-            let serial = self.serial.fetch_add(1, Ordering::Relaxed);
-            let next = (serial as usize) % self.threads;
-            if next < self.conns.len() {
-                self.conns[next].write(data).await;
-            }
-        }
+        // Skip slots whose connection is down and being rebuilt
+        let conn = (0..self.conns.len())
+            .map(|i| self.conns[(first + i) % self.conns.len()].read().clone())
+            .find(|conn| conn.is_connected());
+        let Some(conn) = conn else {
+            debug!("No connection available, packet dropped");
+            return;
+        };
+
+        conn.write_packet(pkt.as_bytes().to_vec()).await;
     }
 
     pub fn stop(&self) {
-        for conn in &self.conns {
-            let close_tx = conn.close_tx();
+        self.stopped.store(true, Ordering::Relaxed);
+        for slot in self.conns.iter() {
+            let close_tx = slot.read().close_tx();
             tokio::spawn(async move {
                 let _ = close_tx.send(()).await;
             });
@@ -210,11 +175,189 @@ impl<H: TransportHandler + 'static> Client<H> {
     }
 }
 
-async fn send_ping(conn: &Arc<ClientConn>, _key: &str) {
+/// Keep the connection in slot `index` alive, rebuilding it with
+/// exponential backoff whenever it drops. Returns why it gave up when
+/// reconnecting cannot help, None once the client is stopped
+async fn supervise_connection<H: TransportHandler + 'static>(
+    index: usize,
+    remote_addr: String,
+    psk: Option<Arc<PreSharedKey>>,
+    handler: Arc<H>,
+    conns: Arc<Vec<ConnSlot>>,
+    stopped: Arc<AtomicBool>,
+    lease: Arc<watch::Sender<Option<MessageLease>>>,
+) -> Option<String> {
+    let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(30));
+
+    while !stopped.load(Ordering::Relaxed) {
+        let (quinn_conn, ciphers, new_lease, local_port) = match connect_server(&remote_addr, psk.as_deref(), false).await {
+            Ok(session) => session,
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!(
+                    index = index,
+                    error = %e,
+                    retry_in = ?delay,
+                    "Failed to connect to server"
+                );
+                sleep(delay).await;
+                continue;
+            }
+        };
+
+        // The server would reject every ping from addresses it did not lease
+        if let Err(e) = check_lease(&new_lease) {
+            error!(index = index, error = %e, "Cannot keep the configured address");
+            e.close(&quinn_conn);
+            return Some(e.to_string());
+        }
+
+        // Addresses are configured once, from the first lease
+        lease.send_if_modified(|current| match current {
+            None => {
+                *current = Some(new_lease);
+                true
+            }
+            Some(current) => {
+                if *current != new_lease {
+                    warn!(
+                        index = index,
+                        leased = ?new_lease,
+                        in_use = ?current,
+                        "Server leased different addresses, restart to apply"
+                    );
+                }
+                false
+            }
+        });
+
+        // Swap the new connection into this slot
+        let (conn, write_rx, close_rx) = ClientConn::new(remote_addr.clone(), index);
+        let conn = Arc::new(conn);
+        // The port tells this connection apart from the other threads
+        conn.set_conn_port(local_port.to_string());
+        *conns[index].write() = conn.clone();
+
+        // Register our addresses right away; the server drops packets
+        // from sources it has not seen in a ping
+        let current_lease = lease.borrow().clone();
+        send_ping(&conn, "", current_lease.as_ref()).await;
+
+        let started = Instant::now();
+        let closing_conn = quinn_conn.clone();
+        if let Err(e) = run_client_conn(conn, quinn_conn, ciphers, handler.clone(), write_rx, close_rx).await {
+            error!(index = index, error = %e, "Client connection error");
+        }
+
+        // Reconnecting would only fight the other client for the address
+        if let Some(ConnectionError::ApplicationClosed(close)) = closing_conn.close_reason() {
+            if close.error_code == CLOSE_IP_CONFLICT {
+                let reason = format!(
+                    "Virtual IP conflict ({}), change --ip or --client-id",
+                    String::from_utf8_lossy(&close.reason)
+                );
+                error!(index = index, "{reason}");
+                return Some(reason);
+            }
+        }
+
+        if stopped.load(Ordering::Relaxed) {
+            break;
+        }
+
+        if started.elapsed() >= STABLE_CONN_DURATION {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        warn!(index = index, retry_in = ?delay, "Connection lost, reconnecting");
+        sleep(delay).await;
+    }
+
+    info!(index = index, "Connection supervisor stopped");
+    None
+}
+
+/// Open a QUIC connection to the server and run the session handshake.
+/// Also returns the local UDP port, unique per connection
+pub(super) async fn connect_server(
+    remote_addr: &str,
+    psk: Option<&PreSharedKey>,
+    proxy_only: bool,
+) -> anyhow::Result<(Connection, SessionCiphers, MessageLease, u16)> {
+    // Create QUIC endpoint
+    let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+    
+    // Configure TLS
+    let crypto = client_crypto_config()?;
+
+    let mut transport_config = TransportConfig::default();
+    transport_config.max_concurrent_bidi_streams(1000u32.into());
+    transport_config.receive_window(quinn::VarInt::from_u32(6 * 1024 * 1024));
+    transport_config.send_window(6 * 1024 * 1024);
+    // Detect a dead server quickly so the supervisor can reconnect
+    transport_config.keep_alive_interval(Some(Duration::from_secs(5)));
+    transport_config.max_idle_timeout(Some(Duration::from_secs(15).try_into()?));
+    if !get_config().datagram {
+        // Tell the server not to send datagrams either
+        transport_config.datagram_receive_buffer_size(None);
+    }
+
+    let mut client_config = ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?
+    ));
+    client_config.transport_config(Arc::new(transport_config));
+    endpoint.set_default_client_config(client_config);
+
+    // Resolve server address
+    let server_addr = remote_addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Cannot resolve server address"))?;
+
+    // Connect
     let config = get_config();
+    let connection = endpoint.connect(server_addr, &config.server_name)?.await?;
+    let request = MessageLeaseRequest { identity: config.client_id.clone(), proxy_only };
+    match client_handshake(&connection, psk, config.ciphers[0], &request).await {
+        Ok((ciphers, lease)) => Ok((connection, ciphers, lease, endpoint.local_addr()?.port())),
+        Err(e) => {
+            e.close(&connection);
+            Err(e.into())
+        }
+    }
+}
+
+/// Check a client without `--dynamic-ip` was leased the addresses it is
+/// configured with, if the server leased any
+fn check_lease(lease: &MessageLease) -> Result<(), HandshakeError> {
+    let config = get_config();
+    let addr = |ip: &str| ip.split('/').next().and_then(|ip| ip.parse::<IpAddr>().ok());
+    let leased: Vec<IpAddr> = [&lease.ip, &lease.ip6].into_iter().filter_map(|ip| addr(ip)).collect();
+    if config.dynamic_ip || leased.is_empty() {
+        return Ok(());
+    }
+
+    let configured = std::iter::once(config.ip.as_str()).chain(config.ip6.as_deref());
+    if configured.filter_map(addr).all(|ip| leased.contains(&ip)) {
+        Ok(())
+    } else {
+        Err(HandshakeError::LeaseRequired(lease.ip.clone()))
+    }
+}
+
+async fn send_ping(conn: &Arc<ClientConn>, _key: &str, lease: Option<&MessageLease>) {
+    let config = get_config();
+
+    // Report the addresses the interface was configured with
+    let (ip, ip6) = match lease {
+        Some(lease) if config.dynamic_ip && !lease.ip.is_empty() => {
+            (lease.ip.as_str(), Some(lease.ip6.as_str()).filter(|ip6| !ip6.is_empty()))
+        }
+        _ => (config.ip.as_str(), config.ip6.as_deref()),
+    };
     
     // Parse IP from CIDR
-    let ip = config.ip.split('/').next().unwrap_or(&config.ip);
+    let ip = ip.split('/').next().unwrap_or(ip);
     let local_addr = format!("{}:{}", ip, conn.get_conn_port());
 
     let ping = MessagePing {
@@ -226,6 +369,11 @@ async fn send_ping(conn: &Arc<ClientConn>, _key: &str) {
         local_private_addr: "not_use".to_string(),
         dc: "client".to_string(),
         ip: ip.to_string(),
+        ip6: ip6
+            .and_then(|ip6| ip6.split('/').next())
+            .unwrap_or_default()
+            .to_string(),
+        routes: config.advertise_routes.iter().map(ToString::to_string).collect(),
     };
 
     let env = Envelope {
@@ -241,53 +389,3 @@ async fn send_ping(conn: &Arc<ClientConn>, _key: &str) {
     let data = env.encode_to_vec();
     conn.write(data).await;
 }
-
-/// Skip server certificate verification (for self-signed certs)
-#[derive(Debug)]
-struct SkipServerVerification;
-
-impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &rustls::pki_types::CertificateDer<'_>,
-        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
-        _server_name: &rustls::pki_types::ServerName<'_>,
-        _ocsp_response: &[u8],
-        _now: rustls::pki_types::UnixTime,
-    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
-        Ok(rustls::client::danger::ServerCertVerified::assertion())
-    }
-
-    fn verify_tls12_signature(
-        &self,
-        _message: &[u8],
-        _cert: &rustls::pki_types::CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
-    }
-
-    fn verify_tls13_signature(
-        &self,
-        _message: &[u8],
-        _cert: &rustls::pki_types::CertificateDer<'_>,
-        _dss: &rustls::DigitallySignedStruct,
-    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
-        Ok(rustls::client::danger::HandshakeSignatureValid::assertion())
-    }
-
-    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-        vec![
-            rustls::SignatureScheme::RSA_PKCS1_SHA256,
-            rustls::SignatureScheme::RSA_PKCS1_SHA384,
-            rustls::SignatureScheme::RSA_PKCS1_SHA512,
-            rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
-            rustls::SignatureScheme::ECDSA_NISTP384_SHA384,
-            rustls::SignatureScheme::ECDSA_NISTP521_SHA512,
-            rustls::SignatureScheme::RSA_PSS_SHA256,
-            rustls::SignatureScheme::RSA_PSS_SHA384,
-            rustls::SignatureScheme::RSA_PSS_SHA512,
-            rustls::SignatureScheme::ED25519,
-        ]
-    }
-}
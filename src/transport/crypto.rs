@@ -1,15 +1,39 @@
-//! Cryptography utilities for AES-GCM encryption
+//! Cryptography utilities for AEAD encryption and session key derivation
 
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit},
     Aes128Gcm, Aes256Gcm, Nonce,
 };
+use argon2::Argon2;
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha2::Sha256;
 use thiserror::Error;
 
-use crate::utils::hash::{md5, sha256};
-
 pub const NONCE_SIZE: usize = 12;
 
+/// Size of the random salt each side contributes to a session
+pub const SALT_SIZE: usize = 32;
+
+/// Fixed Argon2 salt; the pre-shared key is stretched once per process,
+/// per-session randomness comes from the handshake salts
+const PSK_SALT: &[u8] = b"qtun-pre-shared-key-v1";
+
+const CLIENT_TO_SERVER_INFO: &[u8] = b"qtun client to server";
+const SERVER_TO_CLIENT_INFO: &[u8] = b"qtun server to client";
+const KEY_UPDATE_INFO: &[u8] = b"qtun key update";
+
+/// Frames sealed under one key before both sides move to the next key
+pub const FRAMES_PER_KEY: u64 = 1 << 24;
+
+/// Number of recent frame sequence numbers tracked for replay detection
+/// on datagrams, which can arrive out of order
+pub const REPLAY_WINDOW_SIZE: u64 = 8192;
+
 #[derive(Error, Debug)]
 pub enum CryptoError {
     #[error("Encryption failed")]
@@ -20,117 +44,627 @@ pub enum CryptoError {
     InvalidKey,
     #[error("Cipher not match")]
     CipherNotMatch,
+    #[error("Unexpected cipher suite id {0}")]
+    SuiteMismatch(u8),
+    #[error("Replayed or stale frame {0}")]
+    Replay(u64),
+    #[error("Nonce counter exhausted")]
+    NonceExhausted,
 }
 
-/// AES-128-GCM cipher wrapper
-pub struct Aes128GcmCipher {
-    cipher: Aes128Gcm,
-    key_bytes: [u8; 16],
+/// AEAD cipher suites, identified on the wire by `id()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherSuite {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
 }
 
-impl Clone for Aes128GcmCipher {
-    fn clone(&self) -> Self {
-        let cipher = Aes128Gcm::new_from_slice(&self.key_bytes)
-            .expect("Key bytes should be valid");
-        Self {
-            cipher,
-            key_bytes: self.key_bytes,
+impl CipherSuite {
+    pub const ALL: [CipherSuite; 3] = [Self::Aes128Gcm, Self::Aes256Gcm, Self::ChaCha20Poly1305];
+
+    /// Suite id carried in the frame header; 0 means plaintext
+    pub fn id(self) -> u8 {
+        match self {
+            Self::Aes128Gcm => 1,
+            Self::Aes256Gcm => 2,
+            Self::ChaCha20Poly1305 => 3,
         }
     }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|suite| suite.id() == id)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Aes128Gcm => "aes-128-gcm",
+            Self::Aes256Gcm => "aes-256-gcm",
+            Self::ChaCha20Poly1305 => "chacha20-poly1305",
+        }
+    }
+
+    pub fn key_len(self) -> usize {
+        match self {
+            Self::Aes128Gcm => 16,
+            Self::Aes256Gcm | Self::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    /// Create a cipher of this suite from raw key bytes
+    pub fn new_cipher(self, key_bytes: &[u8]) -> Result<Cipher, CryptoError> {
+        Ok(match self {
+            Self::Aes128Gcm => Arc::new(Aes128GcmCipher::new(key_bytes)?),
+            Self::Aes256Gcm => Arc::new(Aes256GcmCipher::new(key_bytes)?),
+            Self::ChaCha20Poly1305 => Arc::new(ChaCha20Poly1305Cipher::new(key_bytes)?),
+        })
+    }
 }
 
-impl Aes128GcmCipher {
-    /// Create a new AES-128-GCM cipher from a key string
-    pub fn new(key: &str) -> Result<Self, CryptoError> {
-        let key_vec = md5(key.as_bytes());
-        let mut key_bytes = [0u8; 16];
-        key_bytes.copy_from_slice(&key_vec);
-        let cipher = Aes128Gcm::new_from_slice(&key_bytes)
-            .map_err(|_| CryptoError::InvalidKey)?;
-        Ok(Self { cipher, key_bytes })
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
     }
+}
 
-    /// Encrypt data with the given nonce
-    pub fn encrypt(&self, nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let nonce = Nonce::from_slice(nonce);
-        self.cipher
-            .encrypt(nonce, plaintext)
-            .map_err(|_| CryptoError::EncryptionFailed)
+impl FromStr for CipherSuite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|suite| suite.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|suite| suite.name()).collect();
+                format!("unknown cipher suite {s}, expected one of {}", names.join(", "))
+            })
     }
+}
+
+/// AEAD cipher used to seal tunnel frames
+pub trait AeadCipher: Send + Sync {
+    fn suite(&self) -> CipherSuite;
+
+    /// Encrypt data with the given nonce
+    fn encrypt(&self, nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError>;
 
     /// Decrypt data with the given nonce
-    pub fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let nonce = Nonce::from_slice(nonce);
-        self.cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|_| CryptoError::CipherNotMatch)
-    }
+    fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError>;
+}
+
+/// Shared handle to a session cipher
+pub type Cipher = Arc<dyn AeadCipher>;
+
+macro_rules! aead_cipher {
+    ($(#[$doc:meta])* $name:ident, $inner:ty, $suite:expr) => {
+        $(#[$doc])*
+        pub struct $name {
+            cipher: $inner,
+        }
+
+        impl $name {
+            /// Create a new cipher from raw key bytes
+            pub fn new(key_bytes: &[u8]) -> Result<Self, CryptoError> {
+                let cipher = <$inner>::new_from_slice(key_bytes)
+                    .map_err(|_| CryptoError::InvalidKey)?;
+                Ok(Self { cipher })
+            }
+        }
+
+        impl AeadCipher for $name {
+            fn suite(&self) -> CipherSuite {
+                $suite
+            }
+
+            fn encrypt(&self, nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+                let nonce = Nonce::from_slice(nonce);
+                self.cipher
+                    .encrypt(nonce, plaintext)
+                    .map_err(|_| CryptoError::EncryptionFailed)
+            }
+
+            fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+                let nonce = Nonce::from_slice(nonce);
+                self.cipher
+                    .decrypt(nonce, ciphertext)
+                    .map_err(|_| CryptoError::CipherNotMatch)
+            }
+        }
+    };
 }
 
-/// AES-256-GCM cipher wrapper
-pub struct Aes256GcmCipher {
-    cipher: Aes256Gcm,
+aead_cipher!(
+    /// AES-128-GCM cipher wrapper
+    Aes128GcmCipher, Aes128Gcm, CipherSuite::Aes128Gcm
+);
+aead_cipher!(
+    /// AES-256-GCM cipher wrapper
+    Aes256GcmCipher, Aes256Gcm, CipherSuite::Aes256Gcm
+);
+aead_cipher!(
+    /// ChaCha20-Poly1305 cipher wrapper, fast on CPUs without AES instructions
+    ChaCha20Poly1305Cipher, ChaCha20Poly1305, CipherSuite::ChaCha20Poly1305
+);
+
+/// Pre-shared key stretched with Argon2id
+pub struct PreSharedKey {
+    key_bytes: [u8; 32],
 }
 
-impl Aes256GcmCipher {
-    /// Create a new AES-256-GCM cipher from a key string
-    pub fn new(key: &str) -> Result<Self, CryptoError> {
-        let key_bytes = sha256(key.as_bytes());
-        let cipher = Aes256Gcm::new_from_slice(&key_bytes)
+impl PreSharedKey {
+    /// Derive the key material from the `--key` password
+    pub fn new(password: &str) -> Result<Self, CryptoError> {
+        let mut key_bytes = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(password.as_bytes(), PSK_SALT, &mut key_bytes)
             .map_err(|_| CryptoError::InvalidKey)?;
-        Ok(Self { cipher })
+        Ok(Self { key_bytes })
     }
+}
 
-    /// Encrypt data with the given nonce
-    pub fn encrypt(&self, nonce: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let nonce = Nonce::from_slice(nonce);
-        self.cipher
-            .encrypt(nonce, plaintext)
-            .map_err(|_| CryptoError::EncryptionFailed)
+/// Derives the successive keys of one direction from its traffic secret
+struct KeySchedule {
+    suite: CipherSuite,
+    hkdf: Hkdf<Sha256>,
+}
+
+impl KeySchedule {
+    fn new(suite: CipherSuite, secret: &[u8]) -> Result<Self, CryptoError> {
+        let hkdf = Hkdf::<Sha256>::from_prk(secret).map_err(|_| CryptoError::InvalidKey)?;
+        Ok(Self { suite, hkdf })
     }
 
-    /// Decrypt data with the given nonce
-    pub fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let nonce = Nonce::from_slice(nonce);
-        self.cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|_| CryptoError::CipherNotMatch)
+    /// Key for the given epoch, one epoch every `FRAMES_PER_KEY` frames
+    fn cipher(&self, epoch: u32) -> Result<Cipher, CryptoError> {
+        let mut key_bytes = [0u8; 32];
+        let key_bytes = &mut key_bytes[..self.suite.key_len()];
+        self.hkdf
+            .expand_multi_info(&[KEY_UPDATE_INFO, &epoch.to_be_bytes()], key_bytes)
+            .map_err(|_| CryptoError::InvalidKey)?;
+        self.suite.new_cipher(key_bytes)
     }
 }
 
-/// Generate a random nonce
-pub fn generate_nonce() -> [u8; NONCE_SIZE] {
+/// Split a frame sequence number into the key epoch and the nonce
+fn sequence_nonce(seq: u64, frames_per_key: u64) -> Result<(u32, [u8; NONCE_SIZE]), CryptoError> {
+    let epoch = u32::try_from(seq / frames_per_key).map_err(|_| CryptoError::NonceExhausted)?;
     let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..4].copy_from_slice(&epoch.to_be_bytes());
+    nonce[4..].copy_from_slice(&(seq % frames_per_key).to_be_bytes());
+    Ok((epoch, nonce))
+}
+
+/// Seals outgoing frames with a monotonically increasing nonce counter,
+/// moving to a fresh key every `FRAMES_PER_KEY` frames
+pub struct SealingKey {
+    schedule: KeySchedule,
+    frames_per_key: u64,
+    next_seq: AtomicU64,
+    current: parking_lot::RwLock<(u32, Cipher)>,
+}
+
+impl SealingKey {
+    fn new(schedule: KeySchedule, frames_per_key: u64) -> Result<Self, CryptoError> {
+        let cipher = schedule.cipher(0)?;
+        Ok(Self {
+            schedule,
+            frames_per_key,
+            next_seq: AtomicU64::new(0),
+            current: parking_lot::RwLock::new((0, cipher)),
+        })
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.schedule.suite
+    }
+
+    /// Encrypt a frame, returning the nonce it was sealed with
+    pub fn seal(&self, plaintext: &[u8]) -> Result<([u8; NONCE_SIZE], Vec<u8>), CryptoError> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let (epoch, nonce) = sequence_nonce(seq, self.frames_per_key)?;
+
+        let cipher = {
+            let current = self.current.read();
+            (current.0 == epoch).then(|| current.1.clone())
+        };
+        let cipher = match cipher {
+            Some(cipher) => cipher,
+            None => {
+                let cipher = self.schedule.cipher(epoch)?;
+                let mut current = self.current.write();
+                if current.0 < epoch {
+                    *current = (epoch, cipher.clone());
+                }
+                cipher
+            }
+        };
+
+        Ok((nonce, cipher.encrypt(&nonce, plaintext)?))
+    }
+}
+
+/// Tracks which recent sequence numbers have been seen
+struct ReplayWindow {
+    /// Highest sequence number accepted so far, plus one
+    top: u64,
+    bitmap: [u64; (REPLAY_WINDOW_SIZE / 64) as usize],
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self {
+            top: 0,
+            bitmap: [0; (REPLAY_WINDOW_SIZE / 64) as usize],
+        }
+    }
+
+    fn bit(seq: u64) -> (usize, u64) {
+        let index = seq % REPLAY_WINDOW_SIZE;
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    /// Whether `seq` fell out of the back of the window
+    fn is_stale(&self, seq: u64) -> bool {
+        seq < self.top && self.top - seq > REPLAY_WINDOW_SIZE
+    }
+
+    /// Whether `seq` is neither too old nor already seen
+    fn check(&self, seq: u64) -> bool {
+        if seq >= self.top {
+            return true;
+        }
+        if self.is_stale(seq) {
+            return false;
+        }
+        let (word, mask) = Self::bit(seq);
+        self.bitmap[word] & mask == 0
+    }
+
+    /// Mark `seq` as seen, returning false if it was a replay
+    fn accept(&mut self, seq: u64) -> bool {
+        if !self.check(seq) {
+            return false;
+        }
+        if seq >= self.top {
+            // Slide forward, clearing the slots that leave the window
+            let advance = seq + 1 - self.top;
+            if advance >= REPLAY_WINDOW_SIZE {
+                self.bitmap = [0; (REPLAY_WINDOW_SIZE / 64) as usize];
+            } else {
+                for old in self.top..=seq {
+                    let (word, mask) = Self::bit(old);
+                    self.bitmap[word] &= !mask;
+                }
+            }
+            self.top = seq + 1;
+        }
+        let (word, mask) = Self::bit(seq);
+        self.bitmap[word] |= mask;
+        true
+    }
+}
+
+/// Opens incoming frames, rejecting replayed nonces
+pub struct OpeningKey {
+    schedule: KeySchedule,
+    frames_per_key: u64,
+    /// Ciphers of the latest two epochs, newest first
+    ciphers: parking_lot::Mutex<Vec<(u32, Cipher)>>,
+    /// Datagram sequences seen
+    window: parking_lot::Mutex<ReplayWindow>,
+    /// Stream frame sequences seen, across all streams
+    stream_window: parking_lot::Mutex<ReplayWindow>,
+}
+
+impl OpeningKey {
+    fn new(schedule: KeySchedule, frames_per_key: u64) -> Result<Self, CryptoError> {
+        let cipher = schedule.cipher(0)?;
+        Ok(Self {
+            schedule,
+            frames_per_key,
+            ciphers: parking_lot::Mutex::new(vec![(0, cipher)]),
+            window: parking_lot::Mutex::new(ReplayWindow::new()),
+            stream_window: parking_lot::Mutex::new(ReplayWindow::new()),
+        })
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.schedule.suite
+    }
+
+    /// Decrypt with the key of `epoch`. The next epoch's key is only kept
+    /// once a frame authenticates under it, so forged frames cannot push
+    /// the live key out
+    fn decrypt(&self, epoch: u32, nonce: &[u8; NONCE_SIZE], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (known, newest) = {
+            let ciphers = self.ciphers.lock();
+            let known = ciphers.iter().find(|(e, _)| *e == epoch).map(|(_, cipher)| cipher.clone());
+            (known, ciphers[0].0)
+        };
+        if let Some(cipher) = known {
+            return cipher.decrypt(nonce, ciphertext);
+        }
+        // Only the next epoch may appear; anything else is forged or stale
+        if newest.checked_add(1) != Some(epoch) {
+            return Err(CryptoError::CipherNotMatch);
+        }
+
+        let cipher = self.schedule.cipher(epoch)?;
+        let plaintext = cipher.decrypt(nonce, ciphertext)?;
+        let mut ciphers = self.ciphers.lock();
+        if ciphers[0].0 < epoch {
+            ciphers.insert(0, (epoch, cipher));
+            ciphers.truncate(2);
+        }
+        Ok(plaintext)
+    }
+
+    /// Epoch and session-wide sequence number of a nonce
+    fn sequence(&self, nonce: &[u8; NONCE_SIZE]) -> Result<(u32, u64), CryptoError> {
+        let epoch = u32::from_be_bytes(nonce[..4].try_into().unwrap());
+        let counter = u64::from_be_bytes(nonce[4..].try_into().unwrap());
+        if counter >= self.frames_per_key {
+            return Err(CryptoError::CipherNotMatch);
+        }
+        Ok((epoch, epoch as u64 * self.frames_per_key + counter))
+    }
+
+    /// Decrypt a frame read from a QUIC stream whose last accepted frame
+    /// was `last`. A stream's frames must be strictly increasing, and no
+    /// frame may be seen twice across the session's streams. Datagrams
+    /// share the counter and may run far ahead, so streams keep their own
+    /// window; a slow stream may continue behind it, but not start there
+    pub fn open_stream(
+        &self,
+        nonce: &[u8; NONCE_SIZE],
+        ciphertext: &[u8],
+        last: &mut Option<u64>,
+    ) -> Result<Vec<u8>, CryptoError> {
+        let (epoch, seq) = self.sequence(nonce)?;
+        let continuing = last.is_some();
+        if last.is_some_and(|last| seq <= last) {
+            return Err(CryptoError::Replay(seq));
+        }
+        let stale = {
+            let window = self.stream_window.lock();
+            let stale = window.is_stale(seq);
+            // Stale frames may only continue a stream
+            let allowed = window.check(seq) || (stale && continuing);
+            if !allowed {
+                return Err(CryptoError::Replay(seq));
+            }
+            stale
+        };
+
+        let plaintext = self.decrypt(epoch, nonce, ciphertext)?;
+        // Only authenticated frames move the window
+        if !stale && !self.stream_window.lock().accept(seq) {
+            return Err(CryptoError::Replay(seq));
+        }
+        *last = Some(seq);
+        Ok(plaintext)
+    }
+
+    /// Decrypt a datagram frame sealed with `nonce`, rejecting replays
+    pub fn open(&self, nonce: &[u8; NONCE_SIZE], ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (epoch, seq) = self.sequence(nonce)?;
+        if !self.window.lock().check(seq) {
+            return Err(CryptoError::Replay(seq));
+        }
+        let plaintext = self.decrypt(epoch, nonce, ciphertext)?;
+        // Only authenticated frames move the window
+        if !self.window.lock().accept(seq) {
+            return Err(CryptoError::Replay(seq));
+        }
+        Ok(plaintext)
+    }
+}
+
+/// Per-direction keys of one session
+#[derive(Clone, Default)]
+pub struct SessionCiphers {
+    /// Key for frames this side sends
+    pub tx: Option<Arc<SealingKey>>,
+    /// Key for frames this side receives
+    pub rx: Option<Arc<OpeningKey>>,
+}
+
+impl SessionCiphers {
+    /// Derive session keys for `suite` with HKDF-SHA256 from the pre-shared
+    /// key and both handshake salts; `None` leaves the session unencrypted
+    pub fn derive(
+        psk: Option<&PreSharedKey>,
+        suite: CipherSuite,
+        client_salt: &[u8; SALT_SIZE],
+        server_salt: &[u8; SALT_SIZE],
+        is_client: bool,
+    ) -> Result<Self, CryptoError> {
+        let Some(psk) = psk else {
+            return Ok(Self::default());
+        };
+
+        let mut salt = [0u8; SALT_SIZE * 2];
+        salt[..SALT_SIZE].copy_from_slice(client_salt);
+        salt[SALT_SIZE..].copy_from_slice(server_salt);
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), &psk.key_bytes);
+
+        let expand = |info: &[u8]| -> Result<KeySchedule, CryptoError> {
+            let mut secret = [0u8; 32];
+            hkdf.expand_multi_info(&[info, &[suite.id()]], &mut secret)
+                .map_err(|_| CryptoError::InvalidKey)?;
+            KeySchedule::new(suite, &secret)
+        };
+        let (tx_info, rx_info) = if is_client {
+            (CLIENT_TO_SERVER_INFO, SERVER_TO_CLIENT_INFO)
+        } else {
+            (SERVER_TO_CLIENT_INFO, CLIENT_TO_SERVER_INFO)
+        };
+
+        Ok(Self {
+            tx: Some(Arc::new(SealingKey::new(expand(tx_info)?, FRAMES_PER_KEY)?)),
+            rx: Some(Arc::new(OpeningKey::new(expand(rx_info)?, FRAMES_PER_KEY)?)),
+        })
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.tx.is_some()
+    }
+}
+
+/// Generate a random handshake salt
+pub fn generate_salt() -> [u8; SALT_SIZE] {
+    let mut salt = [0u8; SALT_SIZE];
     use rand::RngCore;
-    rand::thread_rng().fill_bytes(&mut nonce);
-    nonce
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn session_pair(frames_per_key: u64) -> (SealingKey, OpeningKey) {
+        let secret = [9u8; 32];
+        let suite = CipherSuite::Aes128Gcm;
+        let tx = SealingKey::new(KeySchedule::new(suite, &secret).unwrap(), frames_per_key).unwrap();
+        let rx = OpeningKey::new(KeySchedule::new(suite, &secret).unwrap(), frames_per_key).unwrap();
+        (tx, rx)
+    }
+
     #[test]
-    fn test_aes128_encrypt_decrypt() {
-        let cipher = Aes128GcmCipher::new("test-key").unwrap();
-        let nonce = generate_nonce();
-        let plaintext = b"Hello, World!";
-        
-        let ciphertext = cipher.encrypt(&nonce, plaintext).unwrap();
-        let decrypted = cipher.decrypt(&nonce, &ciphertext).unwrap();
-        
-        assert_eq!(plaintext.to_vec(), decrypted);
+    fn test_encrypt_decrypt() {
+        for suite in CipherSuite::ALL {
+            let key = vec![7u8; suite.key_len()];
+            let cipher = suite.new_cipher(&key).unwrap();
+            let nonce = [1u8; NONCE_SIZE];
+            let plaintext = b"Hello, World!";
+
+            let ciphertext = cipher.encrypt(&nonce, plaintext).unwrap();
+            let decrypted = cipher.decrypt(&nonce, &ciphertext).unwrap();
+
+            assert_eq!(plaintext.to_vec(), decrypted, "{suite}");
+            assert_eq!(cipher.suite(), suite);
+            assert_eq!(CipherSuite::from_id(suite.id()), Some(suite));
+            assert_eq!(suite.name().parse::<CipherSuite>(), Ok(suite));
+        }
     }
 
     #[test]
-    fn test_aes256_encrypt_decrypt() {
-        let cipher = Aes256GcmCipher::new("test-key").unwrap();
-        let nonce = generate_nonce();
-        let plaintext = b"Hello, World!";
-        
-        let ciphertext = cipher.encrypt(&nonce, plaintext).unwrap();
-        let decrypted = cipher.decrypt(&nonce, &ciphertext).unwrap();
-        
-        assert_eq!(plaintext.to_vec(), decrypted);
+    fn test_session_ciphers() {
+        let psk = PreSharedKey::new("test-key").unwrap();
+        let suite = CipherSuite::ChaCha20Poly1305;
+        let client_salt = generate_salt();
+        let server_salt = generate_salt();
+        let client = SessionCiphers::derive(Some(&psk), suite, &client_salt, &server_salt, true).unwrap();
+        let server = SessionCiphers::derive(Some(&psk), suite, &client_salt, &server_salt, false).unwrap();
+
+        let (nonce, ciphertext) = client.tx.as_ref().unwrap().seal(b"ping").unwrap();
+        assert_eq!(server.rx.as_ref().unwrap().open(&nonce, &ciphertext).unwrap(), b"ping");
+
+        // Each direction has its own key
+        assert!(client.rx.as_ref().unwrap().open(&nonce, &ciphertext).is_err());
+
+        // A new salt yields a different session key
+        let other = SessionCiphers::derive(Some(&psk), suite, &generate_salt(), &server_salt, false).unwrap();
+        assert!(other.rx.as_ref().unwrap().open(&nonce, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn test_replay_rejected() {
+        let (tx, rx) = session_pair(FRAMES_PER_KEY);
+        let first = tx.seal(b"first").unwrap();
+        let second = tx.seal(b"second").unwrap();
+
+        // Out of order delivery is fine, a second copy is not
+        assert_eq!(rx.open(&second.0, &second.1).unwrap(), b"second");
+        assert_eq!(rx.open(&first.0, &first.1).unwrap(), b"first");
+        assert!(matches!(rx.open(&first.0, &first.1), Err(CryptoError::Replay(0))));
+
+        // Frames that fell out of the window are rejected
+        let frames: Vec<_> = (0..REPLAY_WINDOW_SIZE + 1).map(|_| tx.seal(b"x").unwrap()).collect();
+        let (last_nonce, last) = frames.last().unwrap();
+        rx.open(last_nonce, last).unwrap();
+        assert!(matches!(rx.open(&frames[0].0, &frames[0].1), Err(CryptoError::Replay(_))));
+        rx.open(&frames[10].0, &frames[10].1).unwrap();
+    }
+
+    #[test]
+    fn test_stream_frames_and_datagrams() {
+        let (tx, rx) = session_pair(FRAMES_PER_KEY);
+        let stream_frame = tx.seal(b"control").unwrap();
+        let datagrams: Vec<_> = (0..REPLAY_WINDOW_SIZE + 1).map(|_| tx.seal(b"packet").unwrap()).collect();
+
+        // Datagrams run more than a window ahead of a slow stream frame
+        for (nonce, ciphertext) in &datagrams {
+            rx.open(nonce, ciphertext).unwrap();
+        }
+        let mut stream = None;
+        assert_eq!(rx.open_stream(&stream_frame.0, &stream_frame.1, &mut stream).unwrap(), b"control");
+
+        // Stream frames leave the datagram window alone
+        let next = tx.seal(b"next").unwrap();
+        assert_eq!(rx.open_stream(&next.0, &next.1, &mut stream).unwrap(), b"next");
+        assert_eq!(rx.open(&next.0, &next.1).unwrap(), b"next");
+        let (nonce, ciphertext) = datagrams.last().unwrap();
+        assert!(matches!(rx.open(nonce, ciphertext), Err(CryptoError::Replay(_))));
+    }
+
+    #[test]
+    fn test_stream_replay_rejected() {
+        let (tx, rx) = session_pair(FRAMES_PER_KEY);
+        let (mut a, mut b) = (None, None);
+        let a1 = tx.seal(b"a1").unwrap();
+        let b1 = tx.seal(b"b1").unwrap();
+        let a2 = tx.seal(b"a2").unwrap();
+
+        // Interleaved streams each see increasing sequences
+        rx.open_stream(&a1.0, &a1.1, &mut a).unwrap();
+        rx.open_stream(&b1.0, &b1.1, &mut b).unwrap();
+        rx.open_stream(&a2.0, &a2.1, &mut a).unwrap();
+
+        // A frame replayed on its own stream, on another or on a new one
+        assert!(matches!(rx.open_stream(&a2.0, &a2.1, &mut a), Err(CryptoError::Replay(2))));
+        assert!(matches!(rx.open_stream(&a2.0, &a2.1, &mut b), Err(CryptoError::Replay(2))));
+        assert!(matches!(rx.open_stream(&a1.0, &a1.1, &mut None), Err(CryptoError::Replay(0))));
+
+        // A new stream cannot start behind the window
+        let frames: Vec<_> = (0..REPLAY_WINDOW_SIZE + 1).map(|_| tx.seal(b"x").unwrap()).collect();
+        let mut c = None;
+        let (nonce, ciphertext) = frames.last().unwrap();
+        rx.open_stream(nonce, ciphertext, &mut c).unwrap();
+        assert!(matches!(rx.open_stream(&frames[0].0, &frames[0].1, &mut None), Err(CryptoError::Replay(_))));
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let (tx, rx) = session_pair(4);
+        let frames: Vec<_> = (0..10).map(|i| tx.seal(&[i]).unwrap()).collect();
+
+        // Epoch in the first nonce bytes, counter restarts per key
+        assert_eq!(&frames[5].0[..4], &1u32.to_be_bytes());
+        assert_eq!(&frames[5].0[4..], &1u64.to_be_bytes());
+        // The same counter under a new key produces a different ciphertext
+        assert_ne!(frames[1].1, frames[5].1);
+
+        for (i, (nonce, ciphertext)) in frames.iter().enumerate() {
+            assert_eq!(rx.open(nonce, ciphertext).unwrap(), [i as u8]);
+        }
+    }
+
+    #[test]
+    fn test_forged_epochs_keep_current_key() {
+        let (tx, rx) = session_pair(4);
+        let frames: Vec<_> = (0..2).map(|i| tx.seal(&[i]).unwrap()).collect();
+        rx.open(&frames[0].0, &frames[0].1).unwrap();
+
+        // Frames claiming the next epochs fail to authenticate
+        for epoch in [1u32, 2] {
+            let mut nonce = [0u8; NONCE_SIZE];
+            nonce[..4].copy_from_slice(&epoch.to_be_bytes());
+            assert!(rx.open(&nonce, &[0u8; 32]).is_err());
+        }
+
+        // and leave the current key in place
+        assert_eq!(rx.open(&frames[1].0, &frames[1].1).unwrap(), [1]);
     }
 }
@@ -0,0 +1,434 @@
+//! Tunnel frame encoding shared by client and server connections
+//!
+//! A frame is `version | suite | length (varint) | payload | nonce`, where
+//! suite is the cipher suite id (0 for plaintext, no nonce) and the length
+//! is a QUIC variable-length integer. Frames travel on the connection's
+//! tunnel stream, or as QUIC datagrams for IP packets.
+
+use std::sync::Arc;
+use std::time::Duration;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use prost::Message;
+use quinn::{Connection, ReadExactError, RecvStream, SendDatagramError, SendStream};
+use thiserror::Error;
+use tokio::sync::mpsc::{self, error::TryRecvError};
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, warn};
+
+use super::crypto::{CryptoError, OpeningKey, SealingKey, NONCE_SIZE};
+use crate::config::{get_config, try_get_config, DEFAULT_MAX_FRAME_SIZE};
+use crate::protocol::{Envelope, MessageBatch, MessagePacket, envelope};
+
+/// Version byte leading every frame
+pub(super) const FRAME_VERSION: u8 = 2;
+
+/// Largest value a QUIC varint can hold
+const VARINT_MAX: u64 = (1 << 62) - 1;
+
+/// AEAD authentication tag appended to every sealed payload
+const TAG_SIZE: usize = 16;
+
+/// Frame bytes around a datagram-sized payload: version, suite, length,
+/// tag and nonce
+const FRAME_OVERHEAD: usize = 2 + 4 + TAG_SIZE + NONCE_SIZE;
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("Unsupported frame version {0}, expected {FRAME_VERSION}")]
+    UnsupportedVersion(u8),
+    #[error("Frame of {len} bytes exceeds the maximum frame size {max}")]
+    TooLarge { len: usize, max: usize },
+    #[error("Truncated frame")]
+    Truncated,
+    #[error("Stream finished")]
+    Finished,
+}
+
+/// Message queued for a connection's write process
+pub enum Outgoing {
+    /// Ping and control messages, always sent on the stream
+    Control(Vec<u8>),
+    /// Raw IP packet, batched with the packets queued behind it and sent
+    /// as a datagram when the peer supports it
+    Packet(Vec<u8>),
+}
+
+fn put_varint(buf: &mut BytesMut, value: u64) {
+    debug_assert!(value <= VARINT_MAX);
+    if value < 1 << 6 {
+        buf.put_u8(value as u8);
+    } else if value < 1 << 14 {
+        buf.put_u16(0x4000 | value as u16);
+    } else if value < 1 << 30 {
+        buf.put_u32(0x8000_0000 | value as u32);
+    } else {
+        buf.put_u64(0xc000_0000_0000_0000 | value);
+    }
+}
+
+/// Total varint size given its first byte
+fn varint_len(first: u8) -> usize {
+    1 << (first >> 6)
+}
+
+fn decode_varint(bytes: &[u8]) -> u64 {
+    let mut value = (bytes[0] & 0x3f) as u64;
+    for byte in &bytes[1..] {
+        value = (value << 8) | *byte as u64;
+    }
+    value
+}
+
+fn check_frame_size(len: usize) -> Result<(), FrameError> {
+    let max = try_get_config().map_or(DEFAULT_MAX_FRAME_SIZE, |config| config.max_frame_size);
+    if len > max {
+        return Err(FrameError::TooLarge { len, max });
+    }
+    Ok(())
+}
+
+/// Encode and encrypt one frame
+pub(super) fn seal_frame(cipher: &Option<Arc<SealingKey>>, data: &[u8]) -> anyhow::Result<BytesMut> {
+    let mut buf = BytesMut::with_capacity(data.len() + 48);
+    buf.put_u8(FRAME_VERSION);
+
+    match cipher {
+        None => {
+            check_frame_size(data.len())?;
+            buf.put_u8(0);
+            put_varint(&mut buf, data.len() as u64);
+            buf.put_slice(data);
+        }
+        Some(cipher) => {
+            let (nonce, encrypted) = cipher.seal(data)?;
+            check_frame_size(encrypted.len())?;
+            buf.put_u8(cipher.suite().id());
+            put_varint(&mut buf, encrypted.len() as u64);
+            buf.put_slice(&encrypted);
+            buf.put_slice(&nonce);
+        }
+    }
+
+    Ok(buf)
+}
+
+/// Decrypt a payload; stream frames pass the sequence last accepted on
+/// their stream, datagrams pass None
+fn open_payload(
+    cipher: &Option<Arc<OpeningKey>>,
+    suite: u8,
+    payload: &[u8],
+    nonce: &[u8; NONCE_SIZE],
+    last_seq: Option<&mut Option<u64>>,
+) -> anyhow::Result<Vec<u8>> {
+    let cipher = cipher.as_ref().ok_or_else(|| anyhow::anyhow!("Cipher not initialized"))?;
+    if suite != cipher.suite().id() {
+        return Err(CryptoError::SuiteMismatch(suite).into());
+    }
+    match last_seq {
+        Some(last_seq) => Ok(cipher.open_stream(nonce, payload, last_seq)?),
+        None => Ok(cipher.open(nonce, payload)?),
+    }
+}
+
+/// Decode and decrypt a frame received as one datagram
+pub(super) fn open_frame(cipher: &Option<Arc<OpeningKey>>, mut frame: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if frame.len() < 3 {
+        return Err(FrameError::Truncated.into());
+    }
+    let version = frame.get_u8();
+    if version != FRAME_VERSION {
+        return Err(FrameError::UnsupportedVersion(version).into());
+    }
+    let suite = frame.get_u8();
+
+    let len_size = varint_len(frame[0]);
+    if frame.len() < len_size {
+        return Err(FrameError::Truncated.into());
+    }
+    let data_len = decode_varint(&frame[..len_size]) as usize;
+    let body = &frame[len_size..];
+    check_frame_size(data_len)?;
+
+    let nonce_size = if suite == 0 { 0 } else { NONCE_SIZE };
+    if body.len() != data_len + nonce_size {
+        return Err(FrameError::Truncated.into());
+    }
+    let (payload, nonce) = body.split_at(data_len);
+    if suite == 0 {
+        return Ok(payload.to_vec());
+    }
+    open_payload(cipher, suite, payload, nonce.try_into().unwrap(), None)
+}
+
+pub(super) async fn write_data(
+    stream: &mut SendStream,
+    cipher: &Option<Arc<SealingKey>>,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let buf = seal_frame(cipher, data)?;
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Reads frames from one stream, rejecting replayed frames by the
+/// sequence last accepted on it
+pub(super) struct FrameReader {
+    stream: RecvStream,
+    /// Reused between frames, grows up to the maximum frame size
+    buf: Vec<u8>,
+    last_seq: Option<u64>,
+}
+
+impl FrameReader {
+    pub(super) fn new(stream: RecvStream) -> Self {
+        Self { stream, buf: Vec::new(), last_seq: None }
+    }
+
+    /// Read one frame
+    pub(super) async fn read(&mut self, cipher: &Option<Arc<OpeningKey>>) -> anyhow::Result<Vec<u8>> {
+        self.next(cipher).await?.ok_or_else(|| FrameError::Finished.into())
+    }
+
+    /// Read one frame, None when the peer finished the stream between frames
+    pub(super) async fn next(&mut self, cipher: &Option<Arc<OpeningKey>>) -> anyhow::Result<Option<Vec<u8>>> {
+        // Read version, suite and the first length byte
+        let mut header = [0u8; 3];
+        match self.stream.read_exact(&mut header).await {
+            Ok(()) => {}
+            Err(ReadExactError::FinishedEarly(0)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        if header[0] != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(header[0]).into());
+        }
+        let suite = header[1];
+
+        let mut len_bytes = [0u8; 8];
+        let len_size = varint_len(header[2]);
+        len_bytes[0] = header[2];
+        self.stream.read_exact(&mut len_bytes[1..len_size]).await?;
+        let data_len = decode_varint(&len_bytes[..len_size]) as usize;
+        check_frame_size(data_len)?;
+
+        // Read data
+        self.buf.resize(data_len, 0);
+        self.stream.read_exact(&mut self.buf).await?;
+
+        if suite == 0 {
+            Ok(Some(self.buf.clone()))
+        } else {
+            // Read nonce
+            let mut nonce = [0u8; NONCE_SIZE];
+            self.stream.read_exact(&mut nonce).await?;
+
+            open_payload(cipher, suite, &self.buf, &nonce, Some(&mut self.last_seq)).map(Some)
+        }
+    }
+}
+
+/// Send a frame, using a datagram for packets when possible and falling
+/// back to the stream otherwise
+async fn write_frame(
+    connection: &Connection,
+    stream: &mut SendStream,
+    cipher: &Option<Arc<SealingKey>>,
+    data: &[u8],
+    is_packet: bool,
+) -> anyhow::Result<()> {
+    let frame = match seal_frame(cipher, data) {
+        Ok(frame) => frame.freeze(),
+        // An oversized packet is dropped like any lost packet
+        Err(e) if is_packet && e.is::<FrameError>() => {
+            warn!(error = %e, "Packet dropped");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let datagram = is_packet && get_config().datagram;
+
+    if datagram && connection.max_datagram_size().is_some_and(|max| frame.len() <= max) {
+        match connection.send_datagram(frame.clone()) {
+            Ok(()) => return Ok(()),
+            Err(SendDatagramError::ConnectionLost(e)) => return Err(e.into()),
+            Err(e) => debug!(error = %e, "Datagram not sent, using stream"),
+        }
+    }
+
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+/// Bytes a packet adds to a batch envelope
+fn batch_entry_len(payload: &[u8]) -> usize {
+    let varint_len = |len: usize| prost::encoding::encoded_len_varint(len as u64);
+    let len = 1 + varint_len(payload.len()) + payload.len();
+    1 + varint_len(len) + len
+}
+
+/// Byte budget of one batch, kept within a datagram when datagrams are usable
+fn batch_budget(connection: &Connection) -> usize {
+    let config = get_config();
+    let mut budget = config.batch_size.min(config.max_frame_size.saturating_sub(TAG_SIZE));
+    if config.datagram {
+        if let Some(max) = connection.max_datagram_size() {
+            budget = budget.min(max.saturating_sub(FRAME_OVERHEAD));
+        }
+    }
+    budget
+}
+
+/// Drain packets queued behind `first` into one envelope until the byte
+/// budget is reached or nothing more arrives within `delay`. Returns the
+/// encoded envelope and the message that did not fit, if any
+async fn collect_batch(
+    first: Vec<u8>,
+    write_rx: &mut mpsc::Receiver<Outgoing>,
+    budget: usize,
+    delay: Duration,
+) -> (Vec<u8>, Option<Outgoing>) {
+    let deadline = Instant::now() + delay;
+    let mut size = batch_entry_len(&first);
+    let mut packets = vec![MessagePacket { payload: first }];
+
+    let rest = loop {
+        if size >= budget {
+            break None;
+        }
+        let next = match write_rx.try_recv() {
+            Ok(next) => next,
+            Err(TryRecvError::Empty) if !delay.is_zero() => {
+                match timeout_at(deadline, write_rx.recv()).await {
+                    Ok(Some(next)) => next,
+                    _ => break None,
+                }
+            }
+            Err(_) => break None,
+        };
+        match next {
+            Outgoing::Packet(payload) if size + batch_entry_len(&payload) <= budget => {
+                size += batch_entry_len(&payload);
+                packets.push(MessagePacket { payload });
+            }
+            other => break Some(other),
+        }
+    };
+
+    // A lone packet keeps the plain packet envelope
+    let r#type = if packets.len() == 1 {
+        envelope::Type::Packet(packets.pop().unwrap())
+    } else {
+        envelope::Type::Batch(MessageBatch { packets })
+    };
+    (Envelope { r#type: Some(r#type) }.encode_to_vec(), rest)
+}
+
+/// Send a queued message and, for packets, everything queued behind it
+/// that fits in the same batch frame
+pub(super) async fn write_outgoing(
+    connection: &Connection,
+    stream: &mut SendStream,
+    cipher: &Option<Arc<SealingKey>>,
+    first: Outgoing,
+    write_rx: &mut mpsc::Receiver<Outgoing>,
+) -> anyhow::Result<()> {
+    let delay = Duration::from_micros(get_config().batch_delay_us);
+    let mut next = Some(first);
+
+    while let Some(outgoing) = next.take() {
+        match outgoing {
+            Outgoing::Control(data) => write_frame(connection, stream, cipher, &data, false).await?,
+            Outgoing::Packet(payload) => {
+                let budget = batch_budget(connection);
+                let (data, rest) = collect_batch(payload, write_rx, budget, delay).await;
+                write_frame(connection, stream, cipher, &data, true).await?;
+                next = rest;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Receive datagram frames until the connection closes
+pub(super) async fn read_datagrams(
+    connection: Connection,
+    cipher: Option<Arc<OpeningKey>>,
+    on_data: impl Fn(Vec<u8>),
+) {
+    loop {
+        let datagram: Bytes = match connection.read_datagram().await {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                debug!(error = %e, "Datagram reader stopped");
+                return;
+            }
+        };
+
+        match open_frame(&cipher, &datagram) {
+            Ok(data) => on_data(data),
+            Err(e) => warn!(error = %e, "Invalid datagram dropped"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::crypto::{generate_salt, CipherSuite, PreSharedKey, SessionCiphers};
+
+    #[test]
+    fn test_datagram_frame() {
+        let psk = PreSharedKey::new("test-key").unwrap();
+        let (client_salt, server_salt) = (generate_salt(), generate_salt());
+        let suite = CipherSuite::Aes256Gcm;
+        let client = SessionCiphers::derive(Some(&psk), suite, &client_salt, &server_salt, true).unwrap();
+        let server = SessionCiphers::derive(Some(&psk), suite, &client_salt, &server_salt, false).unwrap();
+
+        let frame = seal_frame(&client.tx, b"packet").unwrap();
+        assert_eq!(open_frame(&server.rx, &frame).unwrap(), b"packet");
+        assert!(open_frame(&server.rx, &frame[..frame.len() - 1]).is_err());
+
+        let plain = seal_frame(&None, b"packet").unwrap();
+        assert_eq!(open_frame(&None, &plain).unwrap(), b"packet");
+    }
+
+    #[test]
+    fn test_large_frame() {
+        for len in [0, 63, 64, 16383, 16384, 70000] {
+            let data = vec![0xab; len];
+            let frame = seal_frame(&None, &data).unwrap();
+            assert_eq!(frame[0], FRAME_VERSION);
+            assert_eq!(open_frame(&None, &frame).unwrap(), data);
+        }
+
+        let data = vec![0; DEFAULT_MAX_FRAME_SIZE + 1];
+        let err = seal_frame(&None, &data).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(FrameError::TooLarge { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_collect_batch() {
+        let (tx, mut rx) = mpsc::channel(16);
+        for i in 1..=3u8 {
+            tx.send(Outgoing::Packet(vec![i; 100])).await.unwrap();
+        }
+        tx.send(Outgoing::Control(b"ping".to_vec())).await.unwrap();
+
+        // The byte budget ends the first batch after three packets
+        let (data, rest) = collect_batch(vec![0; 100], &mut rx, 400, Duration::ZERO).await;
+        let Some(envelope::Type::Batch(batch)) = Envelope::decode(data.as_slice()).unwrap().r#type else {
+            panic!("expected a batch");
+        };
+        let payloads: Vec<_> = batch.packets.into_iter().map(|p| p.payload[0]).collect();
+        assert_eq!(payloads, [0, 1, 2]);
+        let Some(Outgoing::Packet(next)) = rest else {
+            panic!("expected the packet over budget");
+        };
+
+        // A control message ends the next one, a lone packet keeps the plain envelope
+        let (data, rest) = collect_batch(next, &mut rx, 400, Duration::ZERO).await;
+        let env = Envelope::decode(data.as_slice()).unwrap();
+        assert!(matches!(env.r#type, Some(envelope::Type::Packet(p)) if p.payload[0] == 3));
+        assert!(matches!(rest, Some(Outgoing::Control(_))));
+    }
+}
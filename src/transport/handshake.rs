@@ -0,0 +1,353 @@
+//! Session handshake on the first stream of a QUIC connection
+//!
+//! The client opens a stream and sends `MAGIC | version | suite | salt`,
+//! where suite is the cipher suite id or 0 without a key. The server
+//! checks the suite is enabled and answers with its own hello. Both sides then derive
+//! per-direction keys from the pre-shared key and the two salts, and
+//! exchange one encrypted finished frame each way to confirm the key.
+//! Last, the client sends its identity and the server answers with the
+//! virtual addresses leased to it, empty without an address pool or for
+//! a proxy-only client.
+//! The stream is closed afterwards; later streams use the session keys.
+
+use std::time::Duration;
+use prost::Message;
+use quinn::{Connection, RecvStream, VarInt};
+use thiserror::Error;
+use tokio::time::timeout;
+
+use super::crypto::{generate_salt, CipherSuite, CryptoError, PreSharedKey, SessionCiphers, SALT_SIZE};
+use super::frame::{self, FrameReader};
+use crate::protocol::{MessageLease, MessageLeaseRequest};
+
+const MAGIC: &[u8; 4] = b"QTUN";
+pub(super) const PROTOCOL_VERSION: u8 = 5;
+const HELLO_SIZE: usize = MAGIC.len() + 2 + SALT_SIZE;
+
+const CLIENT_FINISHED: &[u8] = b"qtun client finished";
+const SERVER_FINISHED: &[u8] = b"qtun server finished";
+
+pub(super) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// QUIC application close code for peers speaking another protocol version
+pub(super) const CLOSE_UNSUPPORTED_VERSION: VarInt = VarInt::from_u32(2);
+/// QUIC application close code for peers using a different key
+pub(super) const CLOSE_KEY_MISMATCH: VarInt = VarInt::from_u32(3);
+/// QUIC application close code for cipher suites the server does not enable
+pub(super) const CLOSE_UNSUPPORTED_CIPHER: VarInt = VarInt::from_u32(4);
+/// QUIC application close code when no virtual address is left to lease
+pub(super) const CLOSE_POOL_EXHAUSTED: VarInt = VarInt::from_u32(5);
+/// QUIC application close code for clients keeping their own address when
+/// the server leases one from its pool
+pub(super) const CLOSE_LEASE_REQUIRED: VarInt = VarInt::from_u32(7);
+
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("Old qtun protocol without session handshake, upgrade the peer")]
+    NotHandshake,
+    #[error("Unsupported protocol version {0}, expected {PROTOCOL_VERSION}")]
+    UnsupportedVersion(u8),
+    #[error("Pre-shared key mismatch")]
+    KeyMismatch,
+    #[error("Cipher suite {0} is not enabled")]
+    UnsupportedCipher(String),
+    #[error("Virtual address pool exhausted")]
+    PoolExhausted,
+    #[error("Server leases addresses from its pool ({0}), set --dynamic-ip")]
+    LeaseRequired(String),
+    #[error("Handshake timed out")]
+    Timeout,
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl HandshakeError {
+    /// Close the connection, telling the peer why the handshake failed
+    pub(super) fn close(&self, connection: &Connection) {
+        let code = match self {
+            Self::NotHandshake | Self::UnsupportedVersion(_) => CLOSE_UNSUPPORTED_VERSION,
+            Self::KeyMismatch => CLOSE_KEY_MISMATCH,
+            Self::UnsupportedCipher(_) => CLOSE_UNSUPPORTED_CIPHER,
+            Self::PoolExhausted => CLOSE_POOL_EXHAUSTED,
+            Self::LeaseRequired(_) => CLOSE_LEASE_REQUIRED,
+            Self::Timeout | Self::Other(_) => return,
+        };
+        connection.close(code, self.to_string().as_bytes());
+    }
+}
+
+struct Hello {
+    /// Cipher suite, `None` for an unencrypted session
+    suite: Option<CipherSuite>,
+    salt: [u8; SALT_SIZE],
+}
+
+impl Hello {
+    fn encode(&self) -> [u8; HELLO_SIZE] {
+        let mut buf = [0u8; HELLO_SIZE];
+        buf[..MAGIC.len()].copy_from_slice(MAGIC);
+        buf[MAGIC.len()] = PROTOCOL_VERSION;
+        buf[MAGIC.len() + 1] = self.suite.map_or(0, CipherSuite::id);
+        buf[MAGIC.len() + 2..].copy_from_slice(&self.salt);
+        buf
+    }
+
+    async fn read(stream: &mut RecvStream) -> Result<Self, HandshakeError> {
+        // Old peers start with a frame header whose first byte is 0 or 1
+        let mut magic = [0u8; MAGIC.len()];
+        stream.read_exact(&mut magic).await.map_err(anyhow::Error::from)?;
+        if &magic != MAGIC {
+            return Err(HandshakeError::NotHandshake);
+        }
+
+        let mut rest = [0u8; HELLO_SIZE - MAGIC.len()];
+        stream.read_exact(&mut rest).await.map_err(anyhow::Error::from)?;
+        if rest[0] != PROTOCOL_VERSION {
+            return Err(HandshakeError::UnsupportedVersion(rest[0]));
+        }
+
+        let suite = match rest[1] {
+            0 => None,
+            id => Some(
+                CipherSuite::from_id(id)
+                    .ok_or_else(|| HandshakeError::UnsupportedCipher(format!("id {id}")))?,
+            ),
+        };
+
+        let mut salt = [0u8; SALT_SIZE];
+        salt.copy_from_slice(&rest[2..]);
+        Ok(Self { suite, salt })
+    }
+}
+
+/// A finished frame that fails to decrypt was sealed with another key
+fn finished_error(e: anyhow::Error) -> HandshakeError {
+    match e.downcast_ref::<CryptoError>() {
+        Some(CryptoError::CipherNotMatch) => HandshakeError::KeyMismatch,
+        _ => HandshakeError::Other(e),
+    }
+}
+
+/// Run the client side of the handshake on a new connection, returning
+/// the session ciphers and the addresses leased for `request`
+pub(super) async fn client_handshake(
+    connection: &Connection,
+    psk: Option<&PreSharedKey>,
+    suite: CipherSuite,
+    request: &MessageLeaseRequest,
+) -> Result<(SessionCiphers, MessageLease), HandshakeError> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        let (mut send_stream, mut recv_stream) =
+            connection.open_bi().await.map_err(anyhow::Error::from)?;
+
+        let hello = Hello { suite: psk.map(|_| suite), salt: generate_salt() };
+        send_stream.write_all(&hello.encode()).await.map_err(anyhow::Error::from)?;
+
+        let reply = Hello::read(&mut recv_stream).await?;
+        if reply.suite != hello.suite {
+            return Err(HandshakeError::KeyMismatch);
+        }
+
+        let ciphers = SessionCiphers::derive(psk, suite, &hello.salt, &reply.salt, true)
+            .map_err(anyhow::Error::from)?;
+        let mut reader = FrameReader::new(recv_stream);
+        if ciphers.is_secure() {
+            frame::write_data(&mut send_stream, &ciphers.tx, CLIENT_FINISHED).await?;
+            let data = reader.read(&ciphers.rx).await.map_err(finished_error)?;
+            if data != SERVER_FINISHED {
+                return Err(HandshakeError::KeyMismatch);
+            }
+        }
+
+        frame::write_data(&mut send_stream, &ciphers.tx, &request.encode_to_vec()).await?;
+        let data = reader.read(&ciphers.rx).await?;
+        let lease = MessageLease::decode(data.as_slice()).map_err(anyhow::Error::from)?;
+
+        let _ = send_stream.finish();
+        Ok((ciphers, lease))
+    })
+    .await
+    .map_err(|_| HandshakeError::Timeout)?
+    .map_err(|e| match (e, connection.close_reason()) {
+        // Surface the reason the server gave for closing
+        (HandshakeError::Other(_), Some(reason)) => HandshakeError::Other(reason.into()),
+        (e, _) => e,
+    })
+}
+
+/// Run the server side of the handshake on the first stream of a
+/// connection, accepting any of the enabled cipher `suites`. `lease`
+/// assigns addresses to the client's request, None when none are left.
+/// Returns the session ciphers, the request and its lease
+pub(super) async fn server_handshake(
+    connection: &Connection,
+    psk: Option<&PreSharedKey>,
+    suites: &[CipherSuite],
+    lease: impl FnOnce(&MessageLeaseRequest) -> Option<MessageLease>,
+) -> Result<(SessionCiphers, MessageLeaseRequest, MessageLease), HandshakeError> {
+    timeout(HANDSHAKE_TIMEOUT, async {
+        let (mut send_stream, mut recv_stream) =
+            connection.accept_bi().await.map_err(anyhow::Error::from)?;
+
+        let hello = Hello::read(&mut recv_stream).await?;
+        let suite = match (hello.suite, psk) {
+            (Some(suite), Some(_)) if suites.contains(&suite) => suite,
+            (Some(suite), Some(_)) => return Err(HandshakeError::UnsupportedCipher(suite.to_string())),
+            // Unencrypted session, the suite is not used
+            (None, None) => CipherSuite::Aes128Gcm,
+            _ => return Err(HandshakeError::KeyMismatch),
+        };
+
+        let reply = Hello { suite: hello.suite, salt: generate_salt() };
+        send_stream.write_all(&reply.encode()).await.map_err(anyhow::Error::from)?;
+
+        let ciphers = SessionCiphers::derive(psk, suite, &hello.salt, &reply.salt, false)
+            .map_err(anyhow::Error::from)?;
+        let mut reader = FrameReader::new(recv_stream);
+        if ciphers.is_secure() {
+            let data = reader.read(&ciphers.rx).await.map_err(finished_error)?;
+            if data != CLIENT_FINISHED {
+                return Err(HandshakeError::KeyMismatch);
+            }
+            frame::write_data(&mut send_stream, &ciphers.tx, SERVER_FINISHED).await?;
+        }
+
+        let data = reader.read(&ciphers.rx).await?;
+        let request = MessageLeaseRequest::decode(data.as_slice()).map_err(anyhow::Error::from)?;
+        let reply = lease(&request).ok_or(HandshakeError::PoolExhausted)?;
+        frame::write_data(&mut send_stream, &ciphers.tx, &reply.encode_to_vec()).await?;
+
+        let _ = send_stream.finish();
+        Ok((ciphers, request, reply))
+    })
+    .await
+    .map_err(|_| HandshakeError::Timeout)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::ConnectionError;
+    use crate::transport::test_util::quic_pair;
+
+    /// Run both sides of the handshake on a loopback connection pair
+    async fn handshake(
+        client_key: Option<&str>,
+        server_key: Option<&str>,
+    ) -> (
+        (Connection, Result<(SessionCiphers, MessageLease), HandshakeError>),
+        (Connection, Result<(SessionCiphers, MessageLeaseRequest, MessageLease), HandshakeError>),
+    ) {
+        let (client, server) = quic_pair().await;
+        let client_psk = client_key.map(|k| PreSharedKey::new(k).unwrap());
+        let request = MessageLeaseRequest { identity: "client-1".to_string(), proxy_only: false };
+        let server_psk = server_key.map(|k| PreSharedKey::new(k).unwrap());
+
+        let (client_result, server_result) = tokio::join!(
+            client_handshake(&client, client_psk.as_ref(), CipherSuite::Aes256Gcm, &request),
+            async {
+                let result = server_handshake(&server, server_psk.as_ref(), &CipherSuite::ALL, |_| {
+                    Some(MessageLease::default())
+                })
+                .await;
+                // The server closes a failed handshake with its reason
+                if let Err(e) = &result {
+                    e.close(&server);
+                }
+                result
+            },
+        );
+        ((client, client_result), (server, server_result))
+    }
+
+    /// The application close code and reason the client was closed with
+    fn close_reason(client: &Connection) -> (VarInt, String) {
+        match client.close_reason() {
+            Some(ConnectionError::ApplicationClosed(close)) => {
+                (close.error_code, String::from_utf8_lossy(&close.reason).into_owned())
+            }
+            other => panic!("unexpected close reason {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_same_key_agrees() {
+        let ((_client, client_result), (_server, server_result)) =
+            handshake(Some("secret"), Some("secret")).await;
+        let (client_ciphers, _) = client_result.unwrap();
+        let (server_ciphers, request, _) = server_result.unwrap();
+        assert_eq!(request.identity, "client-1");
+        assert!(client_ciphers.is_secure() && server_ciphers.is_secure());
+
+        let client_tx = client_ciphers.tx.as_ref().unwrap();
+        let server_tx = server_ciphers.tx.as_ref().unwrap();
+        let client_rx = client_ciphers.rx.as_ref().unwrap();
+        let server_rx = server_ciphers.rx.as_ref().unwrap();
+
+        // Each side opens what the other sealed
+        let (nonce, ct) = client_tx.seal(b"up").unwrap();
+        assert_eq!(server_rx.open(&nonce, &ct).unwrap(), b"up");
+        let (nonce, ct) = server_tx.seal(b"down").unwrap();
+        assert_eq!(client_rx.open(&nonce, &ct).unwrap(), b"down");
+
+        // The two directions use different keys
+        let (nonce, ct) = client_tx.seal(b"up").unwrap();
+        assert!(client_rx.open(&nonce, &ct).is_err());
+        let (nonce, ct) = server_tx.seal(b"down").unwrap();
+        assert!(server_rx.open(&nonce, &ct).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_only_request() {
+        let (client, server) = quic_pair().await;
+        let psk = PreSharedKey::new("secret").unwrap();
+        let request = MessageLeaseRequest { identity: "client-1".to_string(), proxy_only: true };
+
+        let (client_result, server_result) = tokio::join!(
+            client_handshake(&client, Some(&psk), CipherSuite::Aes128Gcm, &request),
+            server_handshake(&server, Some(&psk), &CipherSuite::ALL, |request| {
+                // The server sees the flag before choosing a lease
+                assert!(request.proxy_only);
+                Some(MessageLease::default())
+            }),
+        );
+        let (_, lease) = client_result.unwrap();
+        assert_eq!(lease, MessageLease::default());
+        assert_eq!(server_result.unwrap().1, request);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_key_mismatch() {
+        let ((client, client_result), (_server, server_result)) =
+            handshake(Some("secret"), Some("another")).await;
+        assert!(matches!(server_result, Err(HandshakeError::KeyMismatch)));
+        assert!(client_result.is_err());
+
+        let (code, reason) = close_reason(&client);
+        assert_eq!(code, CLOSE_KEY_MISMATCH);
+        assert_eq!(reason, HandshakeError::KeyMismatch.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_old_client_rejected() {
+        let (client, server) = quic_pair().await;
+
+        // Old clients open with a plain frame header instead of a hello
+        let client_task = tokio::spawn(async move {
+            let (mut send_stream, _recv_stream) = client.open_bi().await.unwrap();
+            send_stream.write_all(&[0, 0, 0, 5, 1, 2, 3, 4, 5]).await.unwrap();
+            client.closed().await;
+            client
+        });
+
+        let result = server_handshake(&server, None, &CipherSuite::ALL, |_| Some(MessageLease::default())).await;
+        let e = result.err().expect("old client handshake fails");
+        assert!(matches!(e, HandshakeError::NotHandshake));
+        e.close(&server);
+
+        let client = client_task.await.unwrap();
+        let (code, reason) = close_reason(&client);
+        assert_eq!(code, CLOSE_UNSUPPORTED_VERSION);
+        assert!(reason.contains("upgrade the peer"), "{reason}");
+    }
+}
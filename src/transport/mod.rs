@@ -1,21 +1,38 @@
 //! Transport layer module - QUIC based client/server
 
 pub mod crypto;
+pub mod frame;
+pub mod handshake;
 pub mod client_conn;
 pub mod server_conn;
 pub mod client;
 pub mod server;
+pub mod proxy;
+pub mod tls;
+#[cfg(test)]
+mod test_util;
 
 pub use crypto::*;
 pub use client_conn::ClientConn;
 pub use server_conn::ServerConn;
 pub use client::Client;
 pub use server::Server;
+pub use proxy::TunnelDialer;
 
-use crate::iface::PacketIP;
+/// ALPN protocol id negotiated by client and server
+pub(crate) const ALPN_PROTOCOL: &[u8] = b"quic-echo-example";
 
 /// Handler trait for processing data from transport layer
 pub trait TransportHandler: Send + Sync {
     fn client_on_data(&self, data: Vec<u8>);
     fn server_on_data(&self, data: Vec<u8>, conn: std::sync::Arc<ServerConn>);
+
+    /// Lease virtual addresses to a connecting client identity; an empty
+    /// lease leaves addressing to the client, None rejects the client
+    fn server_on_lease(&self, _identity: &str) -> Option<crate::protocol::MessageLease> {
+        Some(Default::default())
+    }
+
+    /// A connection that got a lease from `server_on_lease` closed
+    fn server_on_release(&self, _identity: &str) {}
 }
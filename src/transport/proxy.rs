@@ -0,0 +1,427 @@
+//! SOCKS5 CONNECT sessions carried over QUIC streams
+//!
+//! Each CONNECT opens a new bidirectional stream on the tunnel connection.
+//! The first frame is an encrypted `MessageStreamOpen`, answered by a
+//! `MessageStreamReply`; after that the TCP bytes follow in chunks, each
+//! sealed in a frame with the session keys like any other stream frame.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use async_trait::async_trait;
+use prost::Message;
+use quinn::{Connection, SendStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::sync::Mutex as TokioMutex;
+use tracing::{debug, info, warn};
+
+use super::client::connect_server;
+use super::crypto::{OpeningKey, PreSharedKey, SealingKey, SessionCiphers};
+use super::frame::{self, FrameReader};
+use crate::protocol::{Envelope, MessageStreamOpen, MessageStreamReply, envelope};
+use crate::socks5::{
+    AddrSpec, BoxedReader, BoxedWriter, Connected, DialError, Dialer, NETWORK_UNREACHABLE,
+    RULE_FAILURE, SERVER_FAILURE, SUCCESS_REPLY,
+};
+
+/// Most relayed bytes sealed into one frame
+const RELAY_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Buffer between a SOCKS5 connection and its sealed relay
+const RELAY_PIPE_SIZE: usize = 64 * 1024;
+
+/// SOCKS5 dialer that opens CONNECT sessions through the qtun server
+pub struct TunnelDialer {
+    remote_addr: String,
+    psk: Option<PreSharedKey>,
+    connection: TokioMutex<Option<(Connection, SessionCiphers)>>,
+}
+
+impl TunnelDialer {
+    pub fn new(remote_addr: String, key: String) -> anyhow::Result<Self> {
+        let psk = if !key.is_empty() {
+            Some(PreSharedKey::new(&key)?)
+        } else {
+            info!("Outgoing encryption disabled");
+            None
+        };
+
+        Ok(Self {
+            remote_addr,
+            psk,
+            connection: TokioMutex::new(None),
+        })
+    }
+
+    /// Get the shared tunnel session, reconnecting if it was closed
+    async fn connection(&self) -> anyhow::Result<(Connection, SessionCiphers)> {
+        let mut connection = self.connection.lock().await;
+        if let Some((conn, ciphers)) = connection.as_ref() {
+            if conn.close_reason().is_none() {
+                return Ok((conn.clone(), ciphers.clone()));
+            }
+            warn!(remote_addr = %self.remote_addr, "Tunnel connection lost, reconnecting");
+        }
+
+        let (conn, ciphers, _, _) = connect_server(&self.remote_addr, self.psk.as_ref(), true)
+            .await
+            .inspect_err(|e| warn!(remote_addr = %self.remote_addr, error = %e, "Tunnel connection failed"))?;
+        info!(remote_addr = %self.remote_addr, "Tunnel connection established");
+        *connection = Some((conn.clone(), ciphers.clone()));
+        Ok((conn, ciphers))
+    }
+
+    async fn open_stream(&self, dest: &AddrSpec) -> anyhow::Result<(SendStream, FrameReader, SessionCiphers, MessageStreamReply)> {
+        let (connection, ciphers) = self.connection().await?;
+        let (mut send_stream, recv_stream) = connection.open_bi().await?;
+
+        let env = Envelope {
+            r#type: Some(envelope::Type::StreamOpen(MessageStreamOpen {
+                address: dest.address(),
+            })),
+        };
+        frame::write_data(&mut send_stream, &ciphers.tx, &env.encode_to_vec()).await?;
+
+        let mut reader = FrameReader::new(recv_stream);
+        let data = reader.read(&ciphers.rx).await?;
+        match Envelope::decode(data.as_slice())?.r#type {
+            Some(envelope::Type::StreamReply(reply)) => Ok((send_stream, reader, ciphers, reply)),
+            other => anyhow::bail!("Unexpected reply to stream open: {:?}", other),
+        }
+    }
+}
+
+#[async_trait]
+impl Dialer for TunnelDialer {
+    async fn dial(&self, dest: &AddrSpec) -> Result<Connected, DialError> {
+        let (send_stream, reader, ciphers, reply) = self
+            .open_stream(dest)
+            .await
+            .map_err(|e| DialError::new(NETWORK_UNREACHABLE, e.to_string()))?;
+
+        if reply.reply != SUCCESS_REPLY as u32 {
+            let code = u8::try_from(reply.reply).unwrap_or(SERVER_FAILURE);
+            return Err(DialError::new(code, format!("Server replied {}", reply.reply)));
+        }
+
+        let mut bind = AddrSpec::new();
+        if let Ok(addr) = reply.bind_addr.parse::<SocketAddr>() {
+            bind.ip = Some(addr.ip());
+            bind.port = addr.port();
+        }
+
+        let (reader, writer) = sealed_pipe(send_stream, reader, ciphers);
+        Ok(Connected { reader, writer, bind })
+    }
+
+    fn resolves_remotely(&self) -> bool {
+        true
+    }
+}
+
+/// Seal the bytes read from `src` into frames on `stream`, finishing the
+/// stream at EOF. Returns the number of bytes relayed
+async fn seal_relay<R: AsyncRead + Unpin>(
+    mut src: R,
+    mut stream: SendStream,
+    cipher: &Option<Arc<SealingKey>>,
+) -> anyhow::Result<u64> {
+    let mut buf = vec![0u8; RELAY_CHUNK_SIZE];
+    let mut total = 0u64;
+    loop {
+        let n = src.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        frame::write_data(&mut stream, cipher, &buf[..n]).await?;
+        total += n as u64;
+    }
+    // Propagate EOF so the peer sees a half-close
+    stream.finish()?;
+    Ok(total)
+}
+
+/// Write the payload of each frame on `reader` to `dst`, half-closing it
+/// when the peer finishes the stream. Returns the number of bytes relayed
+async fn open_relay<W: AsyncWrite + Unpin>(
+    mut reader: FrameReader,
+    mut dst: W,
+    cipher: &Option<Arc<OpeningKey>>,
+) -> anyhow::Result<u64> {
+    let mut total = 0u64;
+    while let Some(data) = reader.next(cipher).await? {
+        dst.write_all(&data).await?;
+        total += data.len() as u64;
+    }
+    dst.shutdown().await?;
+    Ok(total)
+}
+
+/// Relay a proxied stream through a local pipe: the returned halves carry
+/// the plain bytes, the QUIC stream carries them sealed
+fn sealed_pipe(send_stream: SendStream, reader: FrameReader, ciphers: SessionCiphers) -> (BoxedReader, BoxedWriter) {
+    let (local, relay) = tokio::io::duplex(RELAY_PIPE_SIZE);
+    let (relay_reader, relay_writer) = tokio::io::split(relay);
+    tokio::spawn(async move {
+        let relayed = tokio::try_join!(
+            seal_relay(relay_reader, send_stream, &ciphers.tx),
+            open_relay(reader, relay_writer, &ciphers.rx),
+        );
+        if let Err(e) = relayed {
+            debug!(error = %e, "Proxy stream relay failed");
+        }
+    });
+
+    let (reader, writer) = tokio::io::split(local);
+    (Box::new(reader), Box::new(writer))
+}
+
+/// The open request when the first frame of a stream starts a proxied
+/// session; any other frame starts the packet tunnel
+pub(super) fn stream_open(first: &[u8]) -> Option<MessageStreamOpen> {
+    match Envelope::decode(first).ok()?.r#type {
+        Some(envelope::Type::StreamOpen(open)) => Some(open),
+        _ => None,
+    }
+}
+
+/// What proxied streams of a session may reach
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ProxyAccess {
+    /// The session is authenticated by a pre-shared key or a client
+    /// certificate; others may not proxy at all
+    pub authenticated: bool,
+    /// Loopback and unspecified targets are reachable
+    pub allow_loopback: bool,
+}
+
+impl ProxyAccess {
+    fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.allow_loopback || !(ip.is_loopback() || ip.is_unspecified())
+    }
+}
+
+/// Resolve and dial the target of a proxied stream, refusing targets the
+/// session may not reach
+async fn dial_target(address: &str, access: ProxyAccess) -> Result<TcpStream, DialError> {
+    if !access.authenticated {
+        return Err(DialError::new(RULE_FAILURE, "Proxy streams need a pre-shared key or a client certificate"));
+    }
+
+    let resolved: Vec<SocketAddr> = lookup_host(address).await?.collect();
+    let allowed: Vec<SocketAddr> = resolved.iter().copied().filter(|addr| access.allows(addr.ip())).collect();
+    if allowed.is_empty() && !resolved.is_empty() {
+        return Err(DialError::new(RULE_FAILURE, "Loopback and unspecified proxy targets are not allowed"));
+    }
+    Ok(TcpStream::connect(allowed.as_slice()).await?)
+}
+
+/// Serve a proxied stream on the server: dial the target and relay data
+pub(super) async fn serve_proxy_stream(
+    mut send_stream: SendStream,
+    reader: FrameReader,
+    open: MessageStreamOpen,
+    ciphers: SessionCiphers,
+    access: ProxyAccess,
+) -> anyhow::Result<()> {
+    let (target, reply) = match dial_target(&open.address, access).await {
+        Ok(target) => {
+            let bind_addr = target.local_addr().map(|a| a.to_string()).unwrap_or_default();
+            let reply = MessageStreamReply {
+                reply: SUCCESS_REPLY as u32,
+                bind_addr,
+            };
+            (Some(target), reply)
+        }
+        Err(e) => {
+            warn!(target = %open.address, error = %e, "Proxy stream connect failed");
+            let reply = MessageStreamReply {
+                reply: e.reply as u32,
+                bind_addr: String::new(),
+            };
+            (None, reply)
+        }
+    };
+
+    let env = Envelope {
+        r#type: Some(envelope::Type::StreamReply(reply)),
+    };
+    frame::write_data(&mut send_stream, &ciphers.tx, &env.encode_to_vec()).await?;
+
+    let Some(target) = target else {
+        let _ = send_stream.finish();
+        return Ok(());
+    };
+
+    debug!(target = %open.address, "Proxy stream established");
+
+    let (mut target_reader, mut target_writer) = target.into_split();
+    let (sent, received) = tokio::try_join!(
+        open_relay(reader, &mut target_writer, &ciphers.rx),
+        seal_relay(&mut target_reader, send_stream, &ciphers.tx),
+    )?;
+
+    info!(
+        target = %open.address,
+        sent = sent,
+        received = received,
+        "Proxy stream closed"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use crate::protocol::MessagePing;
+    use crate::socks5::CONNECTION_REFUSED;
+    use crate::transport::crypto::{generate_salt, CipherSuite};
+    use crate::transport::test_util::quic_pair;
+
+    /// Loopback echo targets stand in for remote hosts
+    const LOOPBACK_ALLOWED: ProxyAccess = ProxyAccess { authenticated: true, allow_loopback: true };
+
+    /// Session ciphers of a client and server sharing a key
+    fn session_ciphers() -> (SessionCiphers, SessionCiphers) {
+        let psk = PreSharedKey::new("proxy").unwrap();
+        let (client_salt, server_salt) = (generate_salt(), generate_salt());
+        let derive = |is_client| {
+            SessionCiphers::derive(Some(&psk), CipherSuite::Aes128Gcm, &client_salt, &server_salt, is_client).unwrap()
+        };
+        (derive(true), derive(false))
+    }
+
+    /// Open a proxied stream to `address` and return its reply, letting
+    /// the server side dispatch the stream by its first frame
+    async fn open_proxy_stream(
+        client: &Connection,
+        server: &Connection,
+        address: String,
+        access: ProxyAccess,
+    ) -> (BoxedReader, BoxedWriter, MessageStreamReply) {
+        let (client_ciphers, server_ciphers) = session_ciphers();
+        let (mut send_stream, recv_stream) = client.open_bi().await.unwrap();
+        let env = Envelope {
+            r#type: Some(envelope::Type::StreamOpen(MessageStreamOpen { address })),
+        };
+        frame::write_data(&mut send_stream, &client_ciphers.tx, &env.encode_to_vec()).await.unwrap();
+
+        let (server_send, server_recv) = server.accept_bi().await.unwrap();
+        let mut server_reader = FrameReader::new(server_recv);
+        let first = server_reader.read(&server_ciphers.rx).await.unwrap();
+        let open = stream_open(&first).expect("stream open dispatched to the proxy");
+        tokio::spawn(serve_proxy_stream(server_send, server_reader, open, server_ciphers, access));
+
+        let mut reader = FrameReader::new(recv_stream);
+        let data = reader.read(&client_ciphers.rx).await.unwrap();
+        let reply = match Envelope::decode(data.as_slice()).unwrap().r#type {
+            Some(envelope::Type::StreamReply(reply)) => reply,
+            other => panic!("unexpected reply {other:?}"),
+        };
+        let (reader, writer) = sealed_pipe(send_stream, reader, client_ciphers);
+        (reader, writer, reply)
+    }
+
+    #[test]
+    fn test_stream_dispatch() {
+        let open = Envelope {
+            r#type: Some(envelope::Type::StreamOpen(MessageStreamOpen { address: "example.com:443".to_string() })),
+        };
+        assert_eq!(stream_open(&open.encode_to_vec()).unwrap().address, "example.com:443");
+
+        let reply = Envelope {
+            r#type: Some(envelope::Type::StreamReply(MessageStreamReply {
+                reply: CONNECTION_REFUSED as u32,
+                bind_addr: "10.0.0.1:1234".to_string(),
+            })),
+        };
+        let decoded = Envelope::decode(reply.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, reply);
+
+        // Pings and garbage start the packet tunnel
+        let ping = Envelope { r#type: Some(envelope::Type::Ping(MessagePing::default())) };
+        assert!(stream_open(&ping.encode_to_vec()).is_none());
+        assert!(stream_open(&[0xff, 0xff]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_stream_relay() {
+        let (client, server) = quic_pair().await;
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = target.accept().await.unwrap();
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await.unwrap();
+            socket.write_all(&received).await.unwrap();
+        });
+
+        let (mut reader, mut writer, reply) = open_proxy_stream(&client, &server, target_addr.to_string(), LOOPBACK_ALLOWED).await;
+        assert_eq!(reply.reply, SUCCESS_REPLY as u32);
+        assert!(reply.bind_addr.parse::<SocketAddr>().is_ok());
+
+        // The half-close reaches the target, which echoes and closes
+        writer.write_all(b"hello").await.unwrap();
+        writer.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        reader.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"hello");
+
+        // A closed port is reported with the SOCKS5 reply code
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let (mut reader, _, reply) = open_proxy_stream(&client, &server, closed.to_string(), LOOPBACK_ALLOWED).await;
+        assert_eq!(reply.reply, CONNECTION_REFUSED as u32);
+        assert!(reply.bind_addr.is_empty());
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_access() {
+        let (client, server) = quic_pair().await;
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        // Loopback targets are refused unless explicitly allowed
+        let access = ProxyAccess { authenticated: true, allow_loopback: false };
+        for address in [target.to_string(), format!("[::ffff:127.0.0.1]:{}", target.port()), "0.0.0.0:80".to_string()] {
+            let (_, _, reply) = open_proxy_stream(&client, &server, address, access).await;
+            assert_eq!(reply.reply, RULE_FAILURE as u32);
+        }
+        assert!(access.allows("192.0.2.1".parse().unwrap()));
+        assert!(!access.allows("::1".parse().unwrap()));
+
+        // Sessions without a key or client certificate may not proxy at all
+        let access = ProxyAccess { authenticated: false, allow_loopback: true };
+        let (_, _, reply) = open_proxy_stream(&client, &server, target.to_string(), access).await;
+        assert_eq!(reply.reply, RULE_FAILURE as u32);
+    }
+
+    #[tokio::test]
+    async fn test_relay_sealed() {
+        let (client, server) = quic_pair().await;
+        let (client_ciphers, server_ciphers) = session_ciphers();
+        let secret = b"GET /secret HTTP/1.1\r\n\r\n".repeat(2000);
+
+        // The plain bytes never appear on the stream
+        let (send_stream, _) = client.open_bi().await.unwrap();
+        seal_relay(secret.as_slice(), send_stream, &client_ciphers.tx).await.unwrap();
+        let (_, mut recv_stream) = server.accept_bi().await.unwrap();
+        let raw = recv_stream.read_to_end(1 << 20).await.unwrap();
+        assert!(raw.len() > secret.len());
+        assert!(!raw.windows(16).any(|w| w == &secret[..16]));
+
+        // The peer opens them in order, across several frames
+        let (send_stream, _) = client.open_bi().await.unwrap();
+        seal_relay(secret.as_slice(), send_stream, &client_ciphers.tx).await.unwrap();
+        let (_, recv_stream) = server.accept_bi().await.unwrap();
+        let mut opened = Vec::new();
+        let relayed = open_relay(FrameReader::new(recv_stream), &mut opened, &server_ciphers.rx).await.unwrap();
+        assert_eq!(relayed, secret.len() as u64);
+        assert_eq!(opened, secret);
+    }
+}
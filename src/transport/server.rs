@@ -1,17 +1,24 @@
 //! QUIC Server implementation
 
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use dashmap::DashMap;
-use quinn::{Endpoint, ServerConfig, TransportConfig};
-use rcgen::{CertifiedKey, generate_simple_self_signed};
-use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use ipnet::IpNet;
+use quinn::{Connection, Endpoint, RecvStream, SendStream, ServerConfig, TransportConfig};
+use rustls::pki_types::CertificateDer;
 use tokio::time::sleep;
 use tracing::{debug, error, info, warn};
 
-use super::server_conn::{ServerConn, run_server_conn};
+use super::crypto::{CryptoError, PreSharedKey, SessionCiphers};
+use super::handshake::server_handshake;
+use super::proxy::{serve_proxy_stream, stream_open, ProxyAccess};
+use super::frame::FrameReader;
+use super::server_conn::{ServerConn, run_server_conn, CLOSE_NOT_ALLOWED};
+use super::tls::{allowed_ips, allowed_subnets, fingerprint, server_crypto_config};
 use super::TransportHandler;
 use crate::config::get_config;
+use crate::protocol::{MessageLease, MessageLeaseRequest};
 
 pub struct Server<H: TransportHandler + 'static> {
     public_addr: String,
@@ -37,14 +44,20 @@ impl<H: TransportHandler + 'static> Server<H> {
     /// Start the server
     pub async fn start(&self) -> anyhow::Result<()> {
         if get_config().server_mode {
-            self.start_listen().await?;
+            let psk = if !self.key.is_empty() {
+                Some(Arc::new(PreSharedKey::new(&self.key)?))
+            } else {
+                warn!("Encryption disabled, no key set");
+                None
+            };
+            self.start_listen(psk).await?;
         }
         Ok(())
     }
 
-    async fn start_listen(&self) -> anyhow::Result<()> {
+    async fn start_listen(&self, psk: Option<Arc<PreSharedKey>>) -> anyhow::Result<()> {
         loop {
-            match self.listen().await {
+            match self.listen(&psk).await {
                 Ok(_) => {
                     info!(addr = %self.public_addr, "Server listen exit");
                 }
@@ -60,7 +73,7 @@ impl<H: TransportHandler + 'static> Server<H> {
         }
     }
 
-    async fn listen(&self) -> anyhow::Result<()> {
+    async fn listen(&self, psk: &Option<Arc<PreSharedKey>>) -> anyhow::Result<()> {
         // Generate TLS config
         let server_config = self.generate_server_config()?;
 
@@ -70,6 +83,9 @@ impl<H: TransportHandler + 'static> Server<H> {
         transport_config.receive_window(quinn::VarInt::from_u32(6 * 1024 * 1024));
         transport_config.send_window(6 * 1024 * 1024);
         transport_config.keep_alive_interval(Some(Duration::from_secs(30)));
+        if !get_config().datagram {
+            transport_config.datagram_receive_buffer_size(None);
+        }
 
         let mut config = server_config;
         config.transport_config(Arc::new(transport_config));
@@ -89,63 +105,20 @@ impl<H: TransportHandler + 'static> Server<H> {
                 }
             };
 
-            let connection = match incoming.await {
-                Ok(conn) => conn,
-                Err(e) => {
-                    warn!(error = %e, "Failed to accept connection");
-                    continue;
-                }
-            };
-
-            debug!(addr = %self.public_addr, "Server accepting stream");
-
-            // Accept stream
-            let (send_stream, recv_stream) = match connection.accept_bi().await {
-                Ok(streams) => streams,
-                Err(e) => {
-                    warn!(error = %e, "Failed to accept stream");
-                    continue;
-                }
-            };
-
-            let remote_addr = connection.remote_address().to_string();
-            info!(from = %remote_addr, "Server new connection");
-
-            // Create ServerConn
-            let (server_conn, write_rx, close_rx) = ServerConn::new(self.key.clone());
-            let server_conn = Arc::new(server_conn);
-
-            // Spawn connection handler
+            // Serve connection in its own task
             let handler = self.handler.clone();
-            let conn_clone = server_conn.clone();
+            let psk = psk.clone();
             let conns = self.conns.clone();
             let conns_reverse = self.conns_reverse.clone();
-            let conn_ptr = Arc::as_ptr(&server_conn) as usize;
-
             tokio::spawn(async move {
-                let cleanup = {
-                    let conns = conns.clone();
-                    let conns_reverse = conns_reverse.clone();
-                    move || {
-                        // Remove connection from maps
-                        if let Some((_, addr)) = conns_reverse.remove(&conn_ptr) {
-                            conns.remove(&addr);
-                        }
-                        warn!(from = %remote_addr, "Server read thread exit");
+                let connection = match incoming.await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!(error = %e, "Failed to accept connection");
+                        return;
                     }
                 };
-
-                if let Err(e) = run_server_conn(
-                    conn_clone,
-                    send_stream,
-                    recv_stream,
-                    handler,
-                    write_rx,
-                    close_rx,
-                    cleanup,
-                ).await {
-                    error!(error = %e, "Server connection error");
-                }
+                serve_connection(connection, handler, psk, conns, conns_reverse).await;
             });
         }
 
@@ -154,17 +127,7 @@ impl<H: TransportHandler + 'static> Server<H> {
     }
 
     fn generate_server_config(&self) -> anyhow::Result<ServerConfig> {
-        // Generate self-signed certificate
-        let subject_alt_names = vec!["localhost".to_string()];
-        let CertifiedKey { cert, key_pair } = generate_simple_self_signed(subject_alt_names)?;
-
-        let cert_der = CertificateDer::from(cert.der().to_vec());
-        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
-
-        let mut server_crypto = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(vec![cert_der], key_der)?;
-        server_crypto.alpn_protocols = vec![b"quic-echo-example".to_vec()];
+        let server_crypto = server_crypto_config()?;
 
         let server_config = ServerConfig::with_crypto(Arc::new(
             quinn::crypto::rustls::QuicServerConfig::try_from(server_crypto)?
@@ -191,13 +154,10 @@ impl<H: TransportHandler + 'static> Server<H> {
     pub fn set_conn(&self, dst: String, server_conn: Arc<ServerConn>) {
         let conn_ptr = Arc::as_ptr(&server_conn) as usize;
         
-        if let Some(existing) = self.conns.get(&dst) {
-            if existing.is_closed() {
-                // Replace closed connection
-                self.conns.insert(dst.clone(), server_conn.clone());
-                self.conns_reverse.insert(conn_ptr, dst);
-            }
-        } else {
+        // Release the map guard before inserting, or the shard deadlocks
+        let replace = self.conns.get(&dst).is_none_or(|existing| existing.is_closed());
+        if replace {
+            // Replace a closed connection
             self.conns.insert(dst.clone(), server_conn.clone());
             self.conns_reverse.insert(conn_ptr, dst);
         }
@@ -211,3 +171,244 @@ impl<H: TransportHandler + 'static> Server<H> {
         }
     }
 }
+
+/// State shared by all streams of one QUIC connection
+struct ConnState<H: TransportHandler + 'static> {
+    connection: Connection,
+    remote_addr: String,
+    /// Client identity: the certificate fingerprint with client auth,
+    /// otherwise the one sent in the handshake
+    identity: String,
+    /// Virtual IPs allowed by the client certificate, if client auth is on
+    allowed_ips: Option<Vec<IpAddr>>,
+    /// Subnets the client certificate lets the client advertise
+    allowed_routes: Option<Vec<IpNet>>,
+    /// Virtual IPs leased to the client, if the server has an address pool
+    leased_ips: Option<Vec<IpAddr>>,
+    /// The client only proxies streams and may not open the packet tunnel
+    proxy_only: bool,
+    handler: Arc<H>,
+    /// Session ciphers agreed in the handshake
+    ciphers: SessionCiphers,
+    conns: Arc<DashMap<String, Arc<ServerConn>>>,
+    conns_reverse: Arc<DashMap<usize, String>>,
+}
+
+/// What a client certificate grants its holder
+struct PeerGrants {
+    /// Certificate fingerprint, identifying the client
+    identity: String,
+    ips: Vec<IpAddr>,
+    routes: Vec<IpNet>,
+}
+
+/// Get the identity, virtual IPs and advertisable subnets granted by the
+/// peer's client certificate
+fn peer_grants(connection: &Connection) -> anyhow::Result<PeerGrants> {
+    let certs = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .ok_or_else(|| anyhow::anyhow!("No client certificate"))?;
+    let cert = certs
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("No client certificate"))?;
+
+    let ips = allowed_ips(cert)?;
+    if ips.is_empty() {
+        anyhow::bail!("Client certificate carries no virtual IP");
+    }
+    Ok(PeerGrants { identity: fingerprint(cert), ips, routes: allowed_subnets(cert)? })
+}
+
+/// Accept streams on a connection until it closes
+async fn serve_connection<H: TransportHandler + 'static>(
+    connection: Connection,
+    handler: Arc<H>,
+    psk: Option<Arc<PreSharedKey>>,
+    conns: Arc<DashMap<String, Arc<ServerConn>>>,
+    conns_reverse: Arc<DashMap<usize, String>>,
+) {
+    let remote_addr = connection.remote_address().to_string();
+
+    let (cert_identity, allowed_ips, allowed_routes) = if get_config().client_ca.is_some() {
+        match peer_grants(&connection) {
+            Ok(grants) => {
+                info!(
+                    from = %remote_addr,
+                    identity = %grants.identity,
+                    allowed_ips = ?grants.ips,
+                    allowed_routes = ?grants.routes,
+                    "Client certificate accepted"
+                );
+                (Some(grants.identity), Some(grants.ips), Some(grants.routes))
+            }
+            Err(e) => {
+                warn!(from = %remote_addr, error = %e, "Client certificate rejected");
+                connection.close(CLOSE_NOT_ALLOWED, e.to_string().as_bytes());
+                return;
+            }
+        }
+    } else {
+        (None, None, None)
+    };
+
+    // Remember a lease taken mid-handshake, so a failure after it releases it
+    let mut acquired = None;
+    let lease = |request: &MessageLeaseRequest| {
+        // Proxy-only sessions have no packet tunnel to address
+        if request.proxy_only {
+            return Some(MessageLease::default());
+        }
+        // A certificate cannot be borrowed by sending another client's identity
+        let identity = cert_identity.as_deref().unwrap_or(&request.identity);
+        let lease = handler.server_on_lease(identity);
+        if lease.as_ref().and_then(lease_ips).is_some() {
+            acquired = Some(identity.to_string());
+        }
+        lease
+    };
+    let handshake = server_handshake(&connection, psk.as_deref(), &get_config().ciphers, lease).await;
+    let (ciphers, identity, proxy_only, lease) = match handshake {
+        Ok((ciphers, request, lease)) => (ciphers, cert_identity.unwrap_or(request.identity), request.proxy_only, lease),
+        Err(e) => {
+            error!(from = %remote_addr, error = %e, "Handshake failed");
+            if let Some(identity) = acquired {
+                handler.server_on_release(&identity);
+            }
+            e.close(&connection);
+            return;
+        }
+    };
+
+    let leased_ips = lease_ips(&lease);
+    if let Some(ips) = &leased_ips {
+        info!(from = %remote_addr, identity = %identity, ips = ?ips, "Virtual IP leased");
+    }
+
+    let state = Arc::new(ConnState {
+        connection: connection.clone(),
+        remote_addr,
+        identity,
+        allowed_ips,
+        allowed_routes,
+        leased_ips,
+        proxy_only,
+        handler: handler.clone(),
+        ciphers,
+        conns,
+        conns_reverse,
+    });
+
+    loop {
+        debug!(from = %state.remote_addr, "Server accepting stream");
+
+        let (send_stream, recv_stream) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(e) => {
+                debug!(from = %state.remote_addr, error = %e, "Connection closed");
+                break;
+            }
+        };
+
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_stream(send_stream, recv_stream, state).await {
+                error!(error = %e, "Server stream error");
+            }
+        });
+    }
+
+    if state.leased_ips.is_some() {
+        handler.server_on_release(&state.identity);
+    }
+}
+
+/// Addresses in a lease, None for an empty lease
+fn lease_ips(lease: &MessageLease) -> Option<Vec<IpAddr>> {
+    let ips: Vec<IpAddr> = [&lease.ip, &lease.ip6]
+        .into_iter()
+        .filter_map(|ip| ip.parse::<IpNet>().ok())
+        .map(|net| net.addr())
+        .collect();
+    (!ips.is_empty()).then_some(ips)
+}
+
+/// Dispatch a new stream on its first frame: either a proxied CONNECT
+/// or the packet tunnel
+async fn serve_stream<H: TransportHandler + 'static>(
+    send_stream: SendStream,
+    recv_stream: RecvStream,
+    state: Arc<ConnState<H>>,
+) -> anyhow::Result<()> {
+    let remote_addr = state.remote_addr.clone();
+
+    let mut reader = FrameReader::new(recv_stream);
+    let first = match reader.read(&state.ciphers.rx).await {
+        Ok(data) => data,
+        Err(e) => {
+            if let Some(CryptoError::CipherNotMatch) = e.downcast_ref::<CryptoError>() {
+                error!(from = %remote_addr, "Fail to match key, break");
+                return Ok(());
+            }
+            return Err(e);
+        }
+    };
+
+    if let Some(open) = stream_open(&first) {
+        debug!(from = %remote_addr, target = %open.address, "Server new proxy stream");
+        let access = ProxyAccess {
+            authenticated: state.ciphers.is_secure() || state.allowed_ips.is_some(),
+            allow_loopback: get_config().proxy_allow_loopback,
+        };
+        return serve_proxy_stream(send_stream, reader, open, state.ciphers.clone(), access).await;
+    }
+
+    // It was leased no address, so it could claim any
+    if state.proxy_only {
+        warn!(from = %remote_addr, "Proxy-only client opened the packet tunnel");
+        state.connection.close(CLOSE_NOT_ALLOWED, b"Proxy-only session cannot open the packet tunnel");
+        return Ok(());
+    }
+
+    info!(from = %remote_addr, "Server new connection");
+
+    // Create ServerConn
+    let (server_conn, write_rx, close_rx) = ServerConn::new(
+        state.ciphers.clone(),
+        state.connection.clone(),
+        state.identity.clone(),
+        state.allowed_ips.clone(),
+        state.allowed_routes.clone(),
+        state.leased_ips.clone(),
+    );
+    let server_conn = Arc::new(server_conn);
+    let conn_ptr = Arc::as_ptr(&server_conn) as usize;
+
+    let conns = state.conns.clone();
+    let conns_reverse = state.conns_reverse.clone();
+    let closing_conn = server_conn.clone();
+    let cleanup = move || {
+        // Remove connection from maps
+        if let Some((_, addr)) = conns_reverse.remove(&conn_ptr) {
+            conns.remove(&addr);
+        }
+        let spoofed = closing_conn.spoofed_count();
+        if spoofed > 0 {
+            warn!(from = %remote_addr, dropped = spoofed, "Packets with unregistered source addresses dropped");
+        }
+        warn!(from = %remote_addr, "Server read thread exit");
+    };
+
+    // Deliver the frame consumed while dispatching
+    state.handler.server_on_data(first, server_conn.clone());
+
+    run_server_conn(
+        server_conn,
+        send_stream,
+        reader,
+        state.handler.clone(),
+        write_rx,
+        close_rx,
+        cleanup,
+    ).await
+}
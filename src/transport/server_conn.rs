@@ -1,59 +1,88 @@
 //! Server connection handling
 
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use bytes::{BufMut, BytesMut};
-use prost::Message;
-use quinn::{RecvStream, SendStream, Connection as QuinnConnection};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use ipnet::IpNet;
+use quinn::{Connection, SendStream, VarInt};
 use tokio::sync::mpsc;
-use tracing::{debug, error, info, warn};
+use tracing::{error, info, warn};
 
-use super::crypto::{Aes128GcmCipher, generate_nonce, NONCE_SIZE, CryptoError};
+use super::crypto::{CryptoError, SealingKey, SessionCiphers};
+use super::frame::{FrameReader, Outgoing, read_datagrams, write_outgoing};
 use super::TransportHandler;
 use crate::iface::PacketIP;
-use crate::protocol::{Envelope, MessagePacket, envelope};
 
-const READ_BUF_SIZE: usize = 65536;
+/// QUIC application close code for clients that are not allowed in
+pub(super) const CLOSE_NOT_ALLOWED: VarInt = VarInt::from_u32(1);
+/// QUIC application close code for a virtual IP owned by another client
+pub(super) const CLOSE_IP_CONFLICT: VarInt = VarInt::from_u32(6);
+
+/// Shortest time between two spoofed source logs of one connection
+const SPOOFED_LOG_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct ServerConn {
-    key: String,
-    cipher: Option<Aes128GcmCipher>,
-    write_tx: mpsc::Sender<Vec<u8>>,
+    connection: Connection,
+    /// Client identity sent in the handshake, shared by its connections
+    identity: String,
+    /// Virtual IPs granted by the client certificate, if client auth is on
+    allowed_ips: Option<Vec<IpAddr>>,
+    /// Subnets the client certificate lets the client advertise
+    allowed_routes: Option<Vec<IpNet>>,
+    /// Virtual IPs leased from the server's address pool, if any
+    leased_ips: Option<Vec<IpAddr>>,
+    ciphers: SessionCiphers,
+    write_tx: mpsc::Sender<Outgoing>,
     close_tx: mpsc::Sender<()>,
     is_closed: AtomicBool,
+    /// Source addresses the client registered: its virtual IPs and
+    /// accepted advertised subnets
+    sources: parking_lot::RwLock<Vec<IpNet>>,
+    /// Packets dropped for a source outside `sources`
+    spoofed: AtomicU64,
+    /// Last time a spoofed packet was logged
+    spoofed_logged: parking_lot::Mutex<Option<Instant>>,
 }
 
 impl ServerConn {
-    pub fn new(key: String) -> (Self, mpsc::Receiver<Vec<u8>>, mpsc::Receiver<()>) {
+    pub fn new(
+        ciphers: SessionCiphers,
+        connection: Connection,
+        identity: String,
+        allowed_ips: Option<Vec<IpAddr>>,
+        allowed_routes: Option<Vec<IpNet>>,
+        leased_ips: Option<Vec<IpAddr>>,
+    ) -> (Self, mpsc::Receiver<Outgoing>, mpsc::Receiver<()>) {
         let (write_tx, write_rx) = mpsc::channel(256);
         let (close_tx, close_rx) = mpsc::channel(1);
 
-        // Initialize cipher if key is provided
-        let cipher = if !key.is_empty() {
-            match Aes128GcmCipher::new(&key) {
-                Ok(c) => Some(c),
-                Err(e) => {
-                    error!("Failed to create cipher: {}", e);
-                    None
-                }
-            }
-        } else {
+        if !ciphers.is_secure() {
             warn!("Incoming encryption disabled");
-            None
-        };
+        }
 
         let conn = Self {
-            key,
-            cipher,
+            connection,
+            identity,
+            allowed_ips,
+            allowed_routes,
+            leased_ips,
+            ciphers,
             write_tx,
             close_tx,
             is_closed: AtomicBool::new(false),
+            sources: parking_lot::RwLock::new(Vec::new()),
+            spoofed: AtomicU64::new(0),
+            spoofed_logged: parking_lot::Mutex::new(None),
         };
 
         (conn, write_rx, close_rx)
     }
 
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
     pub fn is_closed(&self) -> bool {
         self.is_closed.load(Ordering::Relaxed)
     }
@@ -63,7 +92,11 @@ impl ServerConn {
     }
 
     pub async fn write(&self, data: Vec<u8>) {
-        if let Err(e) = self.write_tx.send(data).await {
+        self.queue(Outgoing::Control(data)).await;
+    }
+
+    async fn queue(&self, outgoing: Outgoing) {
+        if let Err(e) = self.write_tx.send(outgoing).await {
             warn!("Failed to send data to write channel: {}", e);
         }
     }
@@ -72,43 +105,97 @@ impl ServerConn {
         let _ = self.close_tx.try_send(());
     }
 
+    /// Check whether the peer may claim the given virtual IP: it must be
+    /// granted by the client certificate and leased, when those apply
+    pub fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+        [&self.allowed_ips, &self.leased_ips]
+            .into_iter()
+            .all(|ips| ips.as_ref().is_none_or(|ips| ips.contains(&ip)))
+    }
+
+    /// Check whether the client certificate, if client auth is on, lets
+    /// the peer advertise a subnet
+    pub fn is_route_allowed(&self, net: &IpNet) -> bool {
+        self.allowed_routes
+            .as_ref()
+            .is_none_or(|nets| nets.iter().any(|allowed| allowed.contains(net)))
+    }
+
+    /// Replace the source addresses the client may send from
+    pub fn set_sources(&self, sources: Vec<IpNet>) {
+        *self.sources.write() = sources;
+    }
+
+    /// Check whether a packet from the client may carry this source IP
+    pub fn is_source_allowed(&self, ip: IpAddr) -> bool {
+        self.sources.read().iter().any(|net| net.contains(&ip))
+    }
+
+    /// Count a packet dropped for a spoofed source. Returns the total
+    /// when it is time to log again, at most once per interval
+    pub fn record_spoofed(&self) -> Option<u64> {
+        let total = self.spoofed.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut logged = self.spoofed_logged.lock();
+        if logged.is_some_and(|at| at.elapsed() < SPOOFED_LOG_INTERVAL) {
+            return None;
+        }
+        *logged = Some(Instant::now());
+        Some(total)
+    }
+
+    /// Packets dropped so far for a spoofed source
+    pub fn spoofed_count(&self) -> u64 {
+        self.spoofed.load(Ordering::Relaxed)
+    }
+
+    /// Close the whole QUIC connection, telling the peer why
+    pub fn reject(&self, reason: &str) {
+        self.set_closed(true);
+        self.connection.close(CLOSE_NOT_ALLOWED, reason.as_bytes());
+    }
+
+    /// Close the whole QUIC connection because its virtual IP belongs
+    /// to another client
+    pub fn conflict(&self, reason: &str) {
+        self.set_closed(true);
+        self.connection.close(CLOSE_IP_CONFLICT, reason.as_bytes());
+    }
+
     /// Send a packet through this connection
     pub async fn send_packet(&self, pkt: &PacketIP) {
-        let env = Envelope {
-            r#type: Some(envelope::Type::Packet(MessagePacket {
-                payload: pkt.as_bytes().to_vec(),
-            })),
-        };
-        
-        let data = env.encode_to_vec();
-        self.write(data).await;
+        // The write process batches packets into envelopes
+        self.queue(Outgoing::Packet(pkt.as_bytes().to_vec())).await;
     }
 }
 
 /// Run the server connection read/write processes
-pub async fn run_server_conn<H: TransportHandler + 'static>(
+pub(super) async fn run_server_conn<H: TransportHandler + 'static>(
     conn: Arc<ServerConn>,
-    mut send_stream: SendStream,
-    mut recv_stream: RecvStream,
+    send_stream: SendStream,
+    reader: FrameReader,
     handler: Arc<H>,
-    mut write_rx: mpsc::Receiver<Vec<u8>>,
-    mut close_rx: mpsc::Receiver<()>,
+    write_rx: mpsc::Receiver<Outgoing>,
+    close_rx: mpsc::Receiver<()>,
     cleanup: impl FnOnce() + Send + 'static,
 ) -> anyhow::Result<()> {
     // Spawn write process
     let write_conn = conn.clone();
-    let write_cipher_key = conn.key.clone();
+    let write_cipher = conn.ciphers.tx.clone();
     let write_handle = tokio::spawn(async move {
-        let cipher = if !write_cipher_key.is_empty() {
-            Aes128GcmCipher::new(&write_cipher_key).ok()
-        } else {
-            None
-        };
-        write_process(write_conn, send_stream, cipher, write_rx, close_rx).await
+        write_process(write_conn, send_stream, write_cipher, write_rx, close_rx).await
     });
 
+    // Packets may also arrive as datagrams
+    let datagram_conn = conn.clone();
+    let datagram_handler = handler.clone();
+    let datagram_handle = tokio::spawn(read_datagrams(
+        conn.connection.clone(),
+        conn.ciphers.rx.clone(),
+        move |data| datagram_handler.server_on_data(data, datagram_conn.clone()),
+    ));
+
     // Run read process
-    let read_result = read_process(conn.clone(), recv_stream, handler).await;
+    let read_result = read_process(conn.clone(), reader, handler).await;
     
     // Mark as closed
     conn.set_closed(true);
@@ -116,6 +203,7 @@ pub async fn run_server_conn<H: TransportHandler + 'static>(
     
     // Wait for write process to finish
     write_handle.abort();
+    datagram_handle.abort();
     
     // Run cleanup
     cleanup();
@@ -126,8 +214,8 @@ pub async fn run_server_conn<H: TransportHandler + 'static>(
 async fn write_process(
     conn: Arc<ServerConn>,
     mut send_stream: SendStream,
-    cipher: Option<Aes128GcmCipher>,
-    mut write_rx: mpsc::Receiver<Vec<u8>>,
+    cipher: Option<Arc<SealingKey>>,
+    mut write_rx: mpsc::Receiver<Outgoing>,
     mut close_rx: mpsc::Receiver<()>,
 ) -> anyhow::Result<()> {
     info!("ServerConn::ProcessWrite Start");
@@ -135,7 +223,7 @@ async fn write_process(
     loop {
         tokio::select! {
             Some(data) = write_rx.recv() => {
-                if let Err(e) = write_data(&mut send_stream, &cipher, &data).await {
+                if let Err(e) = write_outgoing(&conn.connection, &mut send_stream, &cipher, data, &mut write_rx).await {
                     warn!(error = %e, "ServerConn::ProcessWrite End with error");
                     break;
                 }
@@ -155,22 +243,18 @@ async fn write_process(
 
 async fn read_process<H: TransportHandler>(
     conn: Arc<ServerConn>,
-    mut recv_stream: RecvStream,
+    mut reader: FrameReader,
     handler: Arc<H>,
 ) -> anyhow::Result<()> {
-    let mut read_buf = vec![0u8; READ_BUF_SIZE];
-    
     loop {
-        match read_data(&mut recv_stream, &conn.cipher, &mut read_buf).await {
+        match reader.read(&conn.ciphers.rx).await {
             Ok(data) => {
                 handler.server_on_data(data, conn.clone());
             }
             Err(e) => {
-                if let Some(crypto_err) = e.downcast_ref::<CryptoError>() {
-                    if matches!(crypto_err, CryptoError::CipherNotMatch) {
-                        error!("Fail to match key, break");
-                        break;
-                    }
+                if let Some(CryptoError::CipherNotMatch) = e.downcast_ref::<CryptoError>() {
+                    error!("Fail to match key, break");
+                    break;
                 }
                 error!(error = %e, "ServerConn::run conn read fail, break");
                 break;
@@ -181,62 +265,3 @@ async fn read_process<H: TransportHandler>(
     warn!("ServerConn::conn run, exit");
     Ok(())
 }
-
-async fn write_data(
-    stream: &mut SendStream,
-    cipher: &Option<Aes128GcmCipher>,
-    data: &[u8],
-) -> anyhow::Result<()> {
-    let mut buf = BytesMut::with_capacity(data.len() + 32);
-    
-    let secure: u8 = if cipher.is_some() { 1 } else { 0 };
-    buf.put_u8(secure);
-    
-    if secure == 0 {
-        buf.put_u16_le(data.len() as u16);
-        buf.put_slice(data);
-    } else {
-        let cipher = cipher.as_ref().unwrap();
-        let nonce = generate_nonce();
-        let encrypted = cipher.encrypt(&nonce, data)?;
-        
-        buf.put_u16_le(encrypted.len() as u16);
-        buf.put_slice(&encrypted);
-        buf.put_slice(&nonce);
-    }
-    
-    stream.write_all(&buf).await?;
-    Ok(())
-}
-
-async fn read_data(
-    stream: &mut RecvStream,
-    cipher: &Option<Aes128GcmCipher>,
-    buf: &mut [u8],
-) -> anyhow::Result<Vec<u8>> {
-    // Read secure flag and length
-    let mut header = [0u8; 3];
-    stream.read_exact(&mut header).await?;
-    
-    let secure = header[0];
-    let data_len = u16::from_le_bytes([header[1], header[2]]) as usize;
-    
-    if data_len > buf.len() {
-        anyhow::bail!("Data too large: {}", data_len);
-    }
-    
-    // Read data
-    stream.read_exact(&mut buf[..data_len]).await?;
-    
-    if secure == 0 {
-        Ok(buf[..data_len].to_vec())
-    } else {
-        // Read nonce
-        let mut nonce = [0u8; NONCE_SIZE];
-        stream.read_exact(&mut nonce).await?;
-        
-        let cipher = cipher.as_ref().ok_or_else(|| anyhow::anyhow!("Cipher not initialized"))?;
-        let plain = cipher.decrypt(&nonce, &buf[..data_len])?;
-        Ok(plain)
-    }
-}
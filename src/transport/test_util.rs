@@ -0,0 +1,40 @@
+//! Helpers shared by the transport tests
+
+use std::sync::Arc;
+use quinn::{Connection, Endpoint, ServerConfig};
+use rcgen::{generate_simple_self_signed, CertifiedKey};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+
+use super::ALPN_PROTOCOL;
+
+/// A client and server QUIC connection over loopback
+pub(super) async fn quic_pair() -> (Connection, Connection) {
+    let CertifiedKey { cert, key_pair } = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+
+    let mut server_crypto = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.der().clone()], key)
+        .unwrap();
+    server_crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+    let server_config = ServerConfig::with_crypto(Arc::new(
+        quinn::crypto::rustls::QuicServerConfig::try_from(server_crypto).unwrap(),
+    ));
+    let server = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(cert.der().clone()).unwrap();
+    let mut client_crypto = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client_crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+    let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(client_crypto).unwrap(),
+    )));
+
+    let server_addr = server.local_addr().unwrap();
+    let accepting = tokio::spawn(async move { server.accept().await.unwrap().await.unwrap() });
+    let client_conn = client.connect(server_addr, "localhost").unwrap().await.unwrap();
+    (client_conn, accepting.await.unwrap())
+}
@@ -0,0 +1,386 @@
+//! TLS configuration for the QUIC transport
+
+use std::net::IpAddr;
+use std::sync::{Arc, Once};
+use ipnet::IpNet;
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedKey, DnType, IsCa, KeyPair, KeyUsagePurpose,
+    generate_simple_self_signed,
+};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tracing::{info, warn};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+use super::ALPN_PROTOCOL;
+use crate::config::get_config;
+use crate::utils::hash::{sha256, to_hex};
+
+/// Freshly generated certificate and key
+pub struct GeneratedCert {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub fingerprint: String,
+}
+
+/// Generate a certificate for the given subject alt names. It is
+/// self-signed unless the PEM certificate and key of an issuing CA are given.
+pub fn generate_cert(
+    subject_alt_names: Vec<String>,
+    is_ca: bool,
+    issuer: Option<(&str, &str)>,
+) -> anyhow::Result<GeneratedCert> {
+    let mut params = CertificateParams::new(subject_alt_names.clone())?;
+    if let Some(name) = subject_alt_names.first() {
+        params.distinguished_name.push(DnType::CommonName, name.as_str());
+    }
+    if is_ca {
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+    }
+
+    let key_pair = KeyPair::generate()?;
+    let cert = match issuer {
+        Some((issuer_cert_pem, issuer_key_pem)) => {
+            let issuer_key = KeyPair::from_pem(issuer_key_pem)?;
+            let issuer_cert = CertificateParams::from_ca_cert_pem(issuer_cert_pem)?
+                .self_signed(&issuer_key)?;
+            params.signed_by(&key_pair, &issuer_cert, &issuer_key)?
+        }
+        None => params.self_signed(&key_pair)?,
+    };
+
+    Ok(GeneratedCert {
+        cert_pem: cert.pem(),
+        key_pem: key_pair.serialize_pem(),
+        fingerprint: fingerprint(cert.der()),
+    })
+}
+
+/// SHA-256 fingerprint of a DER certificate as lowercase hex
+pub fn fingerprint(cert: &[u8]) -> String {
+    to_hex(&sha256(cert))
+}
+
+/// Load all certificates from a PEM file
+pub fn load_certs(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("Failed to load certificates from {}: {}", path, e))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate found in {}", path);
+    }
+    Ok(certs)
+}
+
+/// Load a private key from a PEM file
+pub fn load_private_key(path: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| anyhow::anyhow!("Failed to load private key from {}: {}", path, e))
+}
+
+/// Build the server TLS config from --cert/--cert-key, or a temporary
+/// self-signed certificate when neither is given
+pub fn server_crypto_config() -> anyhow::Result<rustls::ServerConfig> {
+    let config = get_config();
+
+    let (chain, key) = match (&config.cert, &config.cert_key) {
+        (Some(cert), Some(key)) => (load_certs(cert)?, load_private_key(key)?),
+        (None, None) => {
+            let CertifiedKey { cert, key_pair } =
+                generate_simple_self_signed(vec![config.server_name.clone()])?;
+            warn!(
+                fingerprint = %fingerprint(cert.der()),
+                "Using a temporary self-signed certificate, use gen-cert and --cert/--cert-key to persist it"
+            );
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+            (vec![cert.der().clone()], key)
+        }
+        _ => anyhow::bail!("--cert and --cert-key must be given together"),
+    };
+
+    info!(fingerprint = %fingerprint(&chain[0]), "Server certificate loaded");
+
+    let builder = rustls::ServerConfig::builder();
+    let builder = if let Some(client_ca) = &config.client_ca {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(client_ca)? {
+            roots.add(cert)?;
+        }
+        info!(client_ca = %client_ca, "Client certificate authentication enabled");
+        builder.with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+    } else {
+        builder.with_no_client_auth()
+    };
+
+    let mut server_crypto = builder.with_single_cert(chain, key)?;
+    server_crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+    Ok(server_crypto)
+}
+
+/// Build the client TLS config: pin the server certificate with
+/// --pin-sha256, verify it against --ca, or skip verification only when
+/// --insecure opts in
+pub fn client_crypto_config() -> anyhow::Result<rustls::ClientConfig> {
+    static SKIP_VERIFY_WARNING: Once = Once::new();
+
+    let config = get_config();
+    let builder = rustls::ClientConfig::builder();
+
+    let builder = if let Some(pin) = &config.pin_sha256 {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier::new(pin)?))
+    } else if let Some(ca) = &config.ca {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca)? {
+            roots.add(cert)?;
+        }
+        builder.with_root_certificates(roots)
+    } else if config.insecure {
+        SKIP_VERIFY_WARNING.call_once(|| {
+            warn!("Server certificate is not verified (--insecure), connections are open to MITM");
+        });
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
+    } else {
+        anyhow::bail!("Cannot verify the server certificate, set --ca or --pin-sha256 (or --insecure to skip verification)");
+    };
+
+    let mut crypto = match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => {
+            builder.with_client_auth_cert(load_certs(cert)?, load_private_key(key)?)?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => anyhow::bail!("--client-cert and --client-key must be given together"),
+    };
+    crypto.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+
+    Ok(crypto)
+}
+
+/// Names a certificate is issued for: its SANs as text, then the subject
+/// common name
+fn cert_names(cert: &CertificateDer<'_>) -> anyhow::Result<Vec<String>> {
+    let (_, parsed) = X509Certificate::from_der(cert)
+        .map_err(|e| anyhow::anyhow!("Failed to parse certificate: {}", e))?;
+
+    let mut names = Vec::new();
+    if let Ok(Some(san)) = parsed.subject_alternative_name() {
+        for name in &san.value.general_names {
+            let name = match name {
+                GeneralName::IPAddress(bytes) => match bytes.len() {
+                    4 => <[u8; 4]>::try_from(*bytes).ok().map(|ip| IpAddr::from(ip).to_string()),
+                    16 => <[u8; 16]>::try_from(*bytes).ok().map(|ip| IpAddr::from(ip).to_string()),
+                    _ => None,
+                },
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            };
+            names.extend(name);
+        }
+    }
+
+    for cn in parsed.subject().iter_common_name() {
+        names.extend(cn.as_str().ok().map(str::to_string));
+    }
+    Ok(names)
+}
+
+/// Virtual IPs a client certificate grants: its IP SANs, or DNS SANs and
+/// the subject common name when they parse as an IP
+pub fn allowed_ips(cert: &CertificateDer<'_>) -> anyhow::Result<Vec<IpAddr>> {
+    let mut ips: Vec<IpAddr> = cert_names(cert)?.iter().filter_map(|name| name.parse().ok()).collect();
+    ips.sort();
+    ips.dedup();
+    Ok(ips)
+}
+
+/// Subnets a client certificate lets its holder advertise: its virtual
+/// IPs, and DNS SANs or the common name written as a CIDR subnet
+pub fn allowed_subnets(cert: &CertificateDer<'_>) -> anyhow::Result<Vec<IpNet>> {
+    let mut nets: Vec<IpNet> = cert_names(cert)?
+        .iter()
+        .filter_map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => Some(IpNet::from(ip)),
+            Err(_) => name.parse::<IpNet>().ok().map(|net| net.trunc()),
+        })
+        .collect();
+    nets.sort();
+    nets.dedup();
+    Ok(nets)
+}
+
+fn supported_algorithms() -> WebPkiSupportedAlgorithms {
+    rustls::crypto::ring::default_provider().signature_verification_algorithms
+}
+
+/// Accept only a server certificate with the pinned SHA-256 fingerprint
+#[derive(Debug)]
+pub struct PinnedCertVerifier {
+    fingerprint: String,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl PinnedCertVerifier {
+    /// Create a verifier from a hex fingerprint; colons and case are ignored
+    pub fn new(pin: &str) -> anyhow::Result<Self> {
+        let fingerprint: String = pin
+            .chars()
+            .filter(|c| *c != ':')
+            .collect::<String>()
+            .to_lowercase();
+        if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("Invalid SHA-256 fingerprint: {}", pin);
+        }
+
+        Ok(Self {
+            fingerprint,
+            algorithms: supported_algorithms(),
+        })
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Skip server certificate verification (for self-signed certs)
+#[derive(Debug)]
+struct SkipServerVerification;
+
+impl ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        supported_algorithms().supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pinned_verifier() {
+        let generated = generate_cert(vec!["localhost".to_string()], false, None).unwrap();
+        let cert = CertificateDer::from_pem_slice(generated.cert_pem.as_bytes()).unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+
+        let pin = generated.fingerprint.to_uppercase();
+        let verifier = PinnedCertVerifier::new(&pin).unwrap();
+        assert!(verifier
+            .verify_server_cert(&cert, &[], &server_name, &[], UnixTime::now())
+            .is_ok());
+
+        let other = generate_cert(vec!["localhost".to_string()], false, None).unwrap();
+        let verifier = PinnedCertVerifier::new(&other.fingerprint).unwrap();
+        assert!(verifier
+            .verify_server_cert(&cert, &[], &server_name, &[], UnixTime::now())
+            .is_err());
+
+        assert!(PinnedCertVerifier::new("not-a-fingerprint").is_err());
+    }
+
+    #[test]
+    fn test_allowed_ips() {
+        let ca = generate_cert(vec!["qtun-ca".to_string()], true, None).unwrap();
+        let generated = generate_cert(
+            vec!["10.237.0.3".to_string(), "client-a".to_string(), "10.237.0.2".to_string(), "10.237.0.3".to_string()],
+            false,
+            Some((&ca.cert_pem, &ca.key_pem)),
+        ).unwrap();
+        let cert = CertificateDer::from_pem_slice(generated.cert_pem.as_bytes()).unwrap();
+
+        // Repeated names collapse even when not adjacent
+        let ips = allowed_ips(&cert).unwrap();
+        assert_eq!(ips, vec!["10.237.0.2".parse::<IpAddr>().unwrap(), "10.237.0.3".parse().unwrap()]);
+
+        // Subnets come from the virtual IPs and CIDR names
+        let generated = generate_cert(
+            vec!["10.237.0.2".to_string(), "192.168.50.0/24".to_string()],
+            false,
+            Some((&ca.cert_pem, &ca.key_pem)),
+        ).unwrap();
+        let cert = CertificateDer::from_pem_slice(generated.cert_pem.as_bytes()).unwrap();
+        let nets = allowed_subnets(&cert).unwrap();
+        assert_eq!(nets, vec!["10.237.0.2/32".parse::<IpNet>().unwrap(), "192.168.50.0/24".parse().unwrap()]);
+        assert_eq!(allowed_ips(&cert).unwrap(), vec!["10.237.0.2".parse::<IpAddr>().unwrap()]);
+    }
+}
@@ -0,0 +1,55 @@
+//! Exponential backoff with jitter for reconnect loops
+
+use std::time::Duration;
+use rand::Rng;
+
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            current: base,
+        }
+    }
+
+    /// Get the next delay and double the backoff, capped at `max`.
+    /// The delay is picked uniformly from [current / 2, current].
+    pub fn next_delay(&mut self) -> Duration {
+        let upper = self.current;
+        let lower = upper / 2;
+        self.current = (self.current * 2).min(self.max);
+
+        let jitter_ms = rand::thread_rng().gen_range(0..=(upper - lower).as_millis() as u64);
+        lower + Duration::from_millis(jitter_ms)
+    }
+
+    /// Reset the backoff after a successful attempt
+    pub fn reset(&mut self) {
+        self.current = self.base;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(400));
+
+        let bounds = [(50, 100), (100, 200), (200, 400), (200, 400)];
+        for (lower, upper) in bounds {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_millis(lower) && delay <= Duration::from_millis(upper));
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+}
@@ -1,8 +1,8 @@
 //! Hash utility functions
 
 use md5::{Md5, Digest as Md5Digest};
-use sha1::{Sha1, Digest as Sha1Digest};
-use sha2::{Sha256, Digest as Sha256Digest};
+use sha1::Sha1;
+use sha2::Sha256;
 use base64::{Engine as _, engine::general_purpose};
 
 /// Compute SHA256 hash
@@ -1,7 +1,9 @@
-//! Utils module - hash functions and timer utilities
+//! Utils module - hash functions, timer and backoff utilities
 
+pub mod backoff;
 pub mod hash;
 pub mod timer;
 
 pub use hash::*;
+pub use backoff::Backoff;
 pub use timer::Timer;
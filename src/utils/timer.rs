@@ -1,8 +1,6 @@
 //! Timer utility for scheduled tasks
 
 use std::collections::HashMap;
-use std::future::Future;
-use std::pin::Pin;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::time::interval;